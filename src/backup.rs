@@ -0,0 +1,309 @@
+use ansi_term::Colour;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::errors::{self, Error, ErrorKind};
+use crate::util;
+
+/// A file recorded in a run's backup index.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// Absolute path of the file zotfile wrote.
+    pub path: String,
+    /// Whether the file existed before the run. Files that didn't are removed on rollback.
+    pub existed: bool,
+    /// If the path was a symlink, where it pointed. The link itself is restored, not the file
+    /// it points to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    #[serde(default)]
+    files: Vec<Entry>,
+}
+
+/// The backup store for a single run: `<state dir>/backups/<run id>/`, holding an `index.toml`
+/// and a copy of every overwritten file under `files/`, mirroring its absolute path.
+///
+/// Nothing is created on disk until the first file is saved, so runs that don't write anything
+/// leave no trace.
+#[derive(Debug)]
+pub struct Backup {
+    id: String,
+    dir: PathBuf,
+}
+
+fn backups_dir() -> PathBuf {
+    util::state_dir().join("backups")
+}
+
+impl Backup {
+    pub fn new() -> Self {
+        let id = util::timestamp();
+        let mut dir = backups_dir().join(&id);
+        let mut n = 1;
+
+        while dir.exists() {
+            n += 1;
+            dir = backups_dir().join(format!("{}-{}", id, n));
+        }
+
+        Backup {
+            id: dir.file_name().unwrap().to_string_lossy().into_owned(),
+            dir,
+        }
+    }
+
+    /// Opens a previous run, or the most recent one if `id` is `None`.
+    pub fn open(id: Option<&str>) -> errors::Result<Self> {
+        let id = match id {
+            Some(id) => id.to_owned(),
+            None => runs()?
+                .pop()
+                .ok_or_else(|| Error::new(ErrorKind::Config("no backups found".to_owned())))?,
+        };
+
+        let dir = backups_dir().join(&id);
+        if !dir.is_dir() {
+            return Err(Error::new(ErrorKind::Config(format!(
+                "no backup run `{}`",
+                id
+            ))));
+        }
+
+        Ok(Backup { id, dir })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Records the current contents of `path` (or the fact that it doesn't exist yet) before it
+    /// is overwritten. Only the first save of a path in a run is kept.
+    pub fn save(&self, path: &Path) -> errors::Result<()> {
+        let path = util::absolute_path(path);
+        let path_str = path.to_string_lossy().into_owned();
+
+        if self.entries()?.iter().any(|e| e.path == path_str) {
+            return Ok(());
+        }
+
+        util::create_private_dir(self.dir.parent().expect("backups dir"))?;
+        let metadata = fs::symlink_metadata(&path).ok();
+        let existed = metadata
+            .as_ref()
+            .is_some_and(|m| m.is_file() || m.file_type().is_symlink());
+        let link = match metadata {
+            Some(ref m) if m.file_type().is_symlink() => {
+                Some(fs::read_link(&path)?.to_string_lossy().into_owned())
+            }
+            Some(ref m) if m.is_file() => {
+                let copy = self.copy_path(&path);
+                fs::create_dir_all(copy.parent().unwrap())?;
+                fs::copy(&path, &copy)?;
+                None
+            }
+            _ => None,
+        };
+
+        fs::create_dir_all(&self.dir)?;
+        let index = Index {
+            files: vec![Entry {
+                path: path_str,
+                existed,
+                link,
+            }],
+        };
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join("index.toml"))?;
+        file.write_all(toml::to_string(&index).unwrap().as_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn entries(&self) -> errors::Result<Vec<Entry>> {
+        let index_path = self.dir.join("index.toml");
+        if !index_path.is_file() {
+            return Ok(vec![]);
+        }
+
+        let index: Index = toml::from_str(&util::read_file_to_string(&index_path)?)?;
+        Ok(index.files)
+    }
+
+    /// Restores every file in this run (or only `only`, if given) to its state before the run,
+    /// saving the current contents to `undo` first so the rollback itself can be undone.
+    pub fn restore(&self, only: Option<&Path>, undo: &Backup) -> errors::Result<usize> {
+        let only = only.map(|p| util::absolute_path(p).to_string_lossy().into_owned());
+        let mut restored = 0;
+
+        for entry in self.entries()? {
+            if only.as_ref().is_some_and(|p| *p != entry.path) {
+                continue;
+            }
+
+            let path = Path::new(&entry.path);
+            undo.save(path)?;
+
+            // whatever is there now (possibly a link zotfile made) is replaced, never written
+            // through
+            let present = fs::symlink_metadata(path).is_ok();
+
+            if let Some(ref dest) = entry.link {
+                println!(
+                    "{} {}",
+                    Colour::Yellow.paint("restoring"),
+                    Colour::Green.bold().paint(&entry.path)
+                );
+                util::symlink_atomic(Path::new(dest), path)?;
+            } else if entry.existed {
+                println!(
+                    "{} {}",
+                    Colour::Yellow.paint("restoring"),
+                    Colour::Green.bold().paint(&entry.path)
+                );
+                if present {
+                    fs::remove_file(path)?;
+                }
+                let copy = self.copy_path(path);
+                let mode = fs::metadata(&copy)?.permissions().mode();
+                let attributes = util::Attributes {
+                    mode: Some(mode),
+                    ..Default::default()
+                };
+                util::write_atomic(path, &fs::read(&copy)?, &attributes)?;
+            } else if present {
+                println!(
+                    "{} {}",
+                    Colour::Yellow.paint("removing"),
+                    Colour::Green.bold().paint(&entry.path)
+                );
+                fs::remove_file(path)?;
+            }
+
+            restored += 1;
+        }
+
+        if let (Some(only), 0) = (only, restored) {
+            return Err(Error::new(ErrorKind::Config(format!(
+                "`{}` is not in backup run `{}`",
+                only, self.id
+            ))));
+        }
+
+        Ok(restored)
+    }
+
+    fn copy_path(&self, path: &Path) -> PathBuf {
+        self.dir
+            .join("files")
+            .join(path.strip_prefix("/").unwrap_or(path))
+    }
+}
+
+/// Every backup run id, oldest first.
+pub fn runs() -> errors::Result<Vec<String>> {
+    let dir = backups_dir();
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            ids.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+
+    ids.sort();
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_save_and_restore() {
+        let tmp = std::env::temp_dir().join(format!("zotfile-backup-{}", std::process::id()));
+        let run = Backup {
+            id: "run".to_owned(),
+            dir: tmp.join("backups/run"),
+        };
+        let undo = Backup {
+            id: "undo".to_owned(),
+            dir: tmp.join("backups/undo"),
+        };
+
+        let existing = tmp.join("home/.vimrc");
+        let created = tmp.join("home/.tmux.conf");
+        fs::create_dir_all(existing.parent().unwrap()).unwrap();
+        fs::write(&existing, "set nu\n").unwrap();
+
+        run.save(&existing).unwrap();
+        run.save(&created).unwrap();
+        fs::write(&existing, "set nonu\n").unwrap();
+        fs::write(&created, "set -g mouse on\n").unwrap();
+
+        assert!(run.restore(Some(&tmp.join("home/.zshrc")), &undo).is_err());
+        assert_eq!(run.restore(None, &undo).unwrap(), 2);
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "set nu\n");
+        assert!(!created.exists());
+        assert_eq!(undo.entries().unwrap().len(), 2);
+        let backups = fs::metadata(tmp.join("backups")).unwrap();
+        assert_eq!(backups.permissions().mode() & 0o777, 0o700);
+
+        fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    #[test]
+    fn test_restore_replaced_by_link() {
+        let tmp = std::env::temp_dir().join(format!("zotfile-backup-link-{}", std::process::id()));
+        let run = Backup {
+            id: "run".to_owned(),
+            dir: tmp.join("backups/run"),
+        };
+        let undo = Backup {
+            id: "undo".to_owned(),
+            dir: tmp.join("backups/undo"),
+        };
+
+        let source = tmp.join("repo/modules/s/files/rc");
+        let target = tmp.join("home/rc");
+        let foreign = tmp.join("home/.rc-link");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::create_dir_all(target.parent().unwrap()).unwrap();
+        fs::write(&source, "from the repo\n").unwrap();
+        fs::write(&target, "mine\n").unwrap();
+        std::os::unix::fs::symlink("/elsewhere/rc", &foreign).unwrap();
+
+        run.save(&target).unwrap();
+        run.save(&foreign).unwrap();
+        util::symlink_atomic(&source, &target).unwrap();
+        util::symlink_atomic(&source, &foreign).unwrap();
+
+        assert_eq!(run.restore(None, &undo).unwrap(), 2);
+        assert!(!fs::symlink_metadata(&target)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "mine\n");
+        assert_eq!(fs::read_link(&foreign).unwrap(), Path::new("/elsewhere/rc"));
+        assert_eq!(fs::read_to_string(&source).unwrap(), "from the repo\n");
+
+        // undoing the rollback puts the links back
+        undo.restore(None, &run).unwrap();
+        assert_eq!(fs::read_link(&target).unwrap(), source);
+
+        fs::remove_dir_all(&tmp).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+use crate::errors::{self, Error, ErrorKind};
+
+/// The comment lines delimiting a managed block (`strategy = "block"`): the part of a file a
+/// module owns, inside a file shared with other tools or other modules.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Markers {
+    pub begin: String,
+    pub end: String,
+}
+
+impl Markers {
+    /// `# BEGIN zotfile:<module>` / `# END zotfile:<module>`, using the template's
+    /// `comment_format`.
+    pub fn new(comment_format: &str, module: &str) -> Self {
+        Markers {
+            begin: format!("{}BEGIN zotfile:{}", comment_format, module),
+            end: format!("{}END zotfile:{}", comment_format, module),
+        }
+    }
+
+    /// The byte range of the block in `text`, markers included, or `None` if there isn't one.
+    /// A begin marker without an end marker is an error, since replacing up to the end of the
+    /// file would clobber whatever follows it.
+    fn locate(&self, text: &str) -> Result<Option<Range<usize>>, String> {
+        let mut start = None;
+        let mut offset = 0;
+
+        for line in text.split_inclusive('\n') {
+            let end = offset + line.len();
+            let trimmed = line.trim_end();
+
+            match start {
+                None if trimmed == self.begin.trim_end() => start = Some(offset),
+                Some(start) if trimmed == self.end.trim_end() => return Ok(Some(start..end)),
+                _ => (),
+            }
+
+            offset = end;
+        }
+
+        match start {
+            Some(_) => Err(format!("`{}` has no matching `{}`", self.begin, self.end)),
+            None => Ok(None),
+        }
+    }
+
+    /// The block in `text`, markers included, if there is a complete one.
+    pub fn find<'t>(&self, text: &'t str) -> Option<&'t str> {
+        self.locate(text).ok().flatten().map(|range| &text[range])
+    }
+
+    /// `body` between the markers.
+    pub fn wrap(&self, body: &str) -> String {
+        let mut block = format!("{}\n{}", self.begin, body);
+        if !body.is_empty() && !body.ends_with('\n') {
+            block.push('\n');
+        }
+        block.push_str(&self.end);
+        block.push('\n');
+        block
+    }
+
+    /// `text` with its block replaced by `body`, or with a block appended if it has none.
+    pub fn replace(&self, text: &str, body: &str) -> errors::Result<String> {
+        self.splice(text, &self.wrap(body))
+    }
+
+    /// `text` with its block replaced by `block` (markers included, see `wrap`), or with
+    /// `block` appended if it has none.
+    pub fn splice(&self, text: &str, block: &str) -> errors::Result<String> {
+        Ok(match self.locate(text).map_err(config_error)? {
+            Some(range) => format!("{}{}{}", &text[..range.start], block, &text[range.end..]),
+            None if text.is_empty() => block.to_owned(),
+            None if text.ends_with('\n') => format!("{}\n{}", text, block),
+            None => format!("{}\n\n{}", text, block),
+        })
+    }
+
+    /// `text` without its block.
+    pub fn remove(&self, text: &str) -> errors::Result<String> {
+        Ok(match self.locate(text).map_err(config_error)? {
+            Some(range) => format!("{}{}", &text[..range.start], &text[range.end..]),
+            None => text.to_owned(),
+        })
+    }
+}
+
+fn config_error(message: String) -> Error {
+    Error::new(ErrorKind::Config(message))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_replace() {
+        let markers = Markers::new("# ", "bash");
+        let text = "export A=1\n";
+
+        let added = markers.replace(text, "alias l=ls").unwrap();
+        assert_eq!(
+            added,
+            "export A=1\n\n# BEGIN zotfile:bash\nalias l=ls\n# END zotfile:bash\n"
+        );
+        assert_eq!(
+            markers.find(&added),
+            Some("# BEGIN zotfile:bash\nalias l=ls\n# END zotfile:bash\n")
+        );
+
+        let updated = markers
+            .replace(&format!("{}export B=2\n", added), "alias ll='ls -l'\n")
+            .unwrap();
+        assert_eq!(
+            updated,
+            "export A=1\n\n# BEGIN zotfile:bash\nalias ll='ls -l'\n# END zotfile:bash\nexport B=2\n"
+        );
+
+        assert_eq!(
+            markers.remove(&updated).unwrap(),
+            "export A=1\n\nexport B=2\n"
+        );
+    }
+
+    #[test]
+    fn test_unterminated() {
+        let markers = Markers::new("# ", "bash");
+        let text = "# BEGIN zotfile:bash\nalias l=ls\n";
+
+        assert_eq!(markers.find(text), None);
+        assert!(markers.replace(text, "x").is_err());
+    }
+}
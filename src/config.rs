@@ -1,66 +1,609 @@
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 use std::path::Path;
-use toml;
+use std::sync::OnceLock;
 
-use crate::errors;
+use crate::errors::{self, Error, ErrorKind};
+use crate::facts::Facts;
 use crate::util;
 
 pub type Config = Option<toml::Value>;
-pub type Distro = Option<String>;
 
+/// Loads `targets/<name>.toml` with everything it inherits. Each layer is deep-merged over the
+/// one before: the `[defaults]` table in the repo's `zotfile.toml`, then each target in the
+/// target's `extends` list (with what they inherit in turn), then the target itself.
 pub fn load_target_config(name: &str) -> errors::Result<Config> {
-    let config_path = format!("targets/{}.toml", name);
-    let path = Path::new(&config_path);
-    util::load_toml_file(&path)
+    let mut config = repo_settings()?
+        .and_then(|settings| settings.get("defaults").cloned())
+        .unwrap_or_else(|| toml::Value::Table(Default::default()));
+
+    merge(&mut config, &load_with_ancestors(name, &load_target_file)?);
+    Ok(Some(config))
+}
+
+/// `targets/<name>.toml`, or `None` if there's no such target.
+fn load_target_file(name: &str) -> errors::Result<Config> {
+    let path = Path::new("targets").join(format!("{}.toml", name));
+
+    if path.is_file() {
+        util::load_toml_file(&path)
+    } else {
+        Ok(None)
+    }
+}
+
+/// A target merged over the targets it `extends`, as loaded by `load`. Every ancestor is merged
+/// once, after all of its own ancestors, so a target two parents share (a diamond) can't undo
+/// what the first parent set.
+fn load_with_ancestors<F>(name: &str, load: &F) -> errors::Result<toml::Value>
+where
+    F: Fn(&str) -> errors::Result<Config>,
+{
+    let mut layers = Vec::new();
+    linearize(name, &mut vec![], &mut layers, load)?;
+
+    let mut config = toml::Value::Table(Default::default());
+    for (_, layer) in &layers {
+        merge(&mut config, layer);
+    }
+    Ok(config)
+}
+
+/// Appends `name` to `layers`, after the targets it extends (unless it's already there).
+/// `chain` is the targets being loaded, to catch cycles.
+fn linearize<F>(
+    name: &str,
+    chain: &mut Vec<String>,
+    layers: &mut Vec<(String, toml::Value)>,
+    load: &F,
+) -> errors::Result<()>
+where
+    F: Fn(&str) -> errors::Result<Config>,
+{
+    chain.push(name.to_owned());
+
+    if chain[..chain.len() - 1].contains(&chain[chain.len() - 1]) {
+        return Err(Error::new(ErrorKind::Config(format!(
+            "targets extend each other: {}",
+            chain.join(" -> ")
+        ))));
+    }
+
+    if layers.iter().any(|(layer, _)| layer == name) {
+        chain.pop();
+        return Ok(());
+    }
+
+    let mut target = load(name)?.ok_or_else(|| {
+        Error::new(ErrorKind::Config(match chain.len() {
+            1 => format!("target `{}` not found in targets/", name),
+            n => format!(
+                "target `{}` (extended by `{}`) not found in targets/",
+                name,
+                chain[n - 2]
+            ),
+        }))
+    })?;
+
+    let extends = target.as_table_mut().and_then(|t| t.remove("extends"));
+    let parents = match extends {
+        None => vec![],
+        Some(toml::Value::String(parent)) => vec![parent],
+        Some(toml::Value::Array(parents)) => parents
+            .into_iter()
+            .map(|parent| match parent {
+                toml::Value::String(parent) => Ok(parent),
+                parent => Err(parent),
+            })
+            .collect::<Result<_, _>>()
+            .map_err(|parent| extends_error(name, &parent))?,
+        Some(other) => return Err(extends_error(name, &other)),
+    };
+
+    for parent in &parents {
+        linearize(parent, chain, layers, load)?;
+    }
+
+    chain.pop();
+    layers.push((name.to_owned(), target));
+    Ok(())
+}
+
+fn extends_error(name: &str, value: &toml::Value) -> Error {
+    Error::new(ErrorKind::Config(format!(
+        "targets/{}.toml: `extends` should be a target name or a list of them, not `{}`",
+        name, value
+    )))
+}
+
+/// The repo-level `zotfile.toml`, if there is one.
+fn repo_settings() -> errors::Result<Config> {
+    let path = Path::new("zotfile.toml");
+
+    if path.is_file() {
+        util::load_toml_file(path)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Parses `--set key=value` arguments into a table. Dotted keys set nested values, and values
+/// are read as TOML if they parse (`14`, `true`, `["a", "b"]`), or as strings otherwise.
+pub fn parse_set<'s>(assignments: impl Iterator<Item = &'s str>) -> errors::Result<Config> {
+    let mut set = toml::Value::Table(Default::default());
+
+    for assignment in assignments {
+        let (key, value) = assignment.split_once('=').ok_or_else(|| {
+            Error::new(ErrorKind::Config(format!(
+                "`--set {}` should be `key=value`",
+                assignment
+            )))
+        })?;
+
+        let mut value = format!("value = {}", value)
+            .parse::<toml::Value>()
+            .ok()
+            .and_then(|v| v.get("value").cloned())
+            .unwrap_or_else(|| toml::Value::String(value.to_owned()));
+
+        for part in key.trim().rsplit('.') {
+            let mut table = toml::value::Table::new();
+            table.insert(part.to_owned(), value);
+            value = toml::Value::Table(table);
+        }
+
+        merge(&mut set, &value);
+    }
+
+    Ok(Some(set))
+}
+
+/// Every target under `targets/`, by name, sorted.
+pub fn targets() -> errors::Result<Vec<String>> {
+    let mut names = Vec::new();
+
+    if Path::new("targets").is_dir() {
+        for entry in std::fs::read_dir("targets")? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "toml") {
+                if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+    }
+
+    names.sort();
+    Ok(names)
+}
+
+/// Picks the target for this host from the `[targets.<name>]` rules in the repo's
+/// `zotfile.toml`, in the order they're declared. A rule matches if the hostname matches one
+/// of its `hostname` patterns (`*` and `?` wildcards) and `platform`, if set, is the host's
+/// os, family or distro; a rule with neither matches any host.
+pub fn select_target(host: &HostConfig) -> errors::Result<String> {
+    let settings = repo_settings()?;
+
+    let rules = settings
+        .as_ref()
+        .and_then(|s| s.get("targets"))
+        .and_then(|t| t.as_table());
+
+    if let Some(name) =
+        rules.and_then(|rules| matching_target(rules, &host.hostname, &host.platform))
+    {
+        return Ok(name.to_owned());
+    }
+
+    Err(Error::new(ErrorKind::Config(format!(
+        "no target in zotfile.toml matches host `{}` ({}); pass --target with one of: {}",
+        host.hostname,
+        host.platform.os(),
+        targets()?.join(", ")
+    ))))
+}
+
+/// The first of `rules` (`[targets.<name>]` tables) matching `hostname` and `platform`.
+fn matching_target<'r>(
+    rules: &'r toml::value::Table,
+    hostname: &str,
+    platform: &Platform,
+) -> Option<&'r str> {
+    rules
+        .iter()
+        .find(|(_, rule)| rule_matches(rule, hostname, platform))
+        .map(|(name, _)| name.as_str())
+}
+
+fn rule_matches(rule: &toml::Value, hostname: &str, platform: &Platform) -> bool {
+    let hostname = hostname.to_lowercase();
+    let hostname_matches = match rule.get("hostname") {
+        None => true,
+        Some(toml::Value::String(pattern)) => util::glob_match(&pattern.to_lowercase(), &hostname),
+        Some(toml::Value::Array(patterns)) => patterns
+            .iter()
+            .filter_map(|p| p.as_str())
+            .any(|p| util::glob_match(&p.to_lowercase(), &hostname)),
+        Some(_) => false,
+    };
+
+    let platform_matches = match rule.get("platform").and_then(|p| p.as_str()) {
+        None => true,
+        Some(name) => {
+            name == platform.os()
+                || Some(name) == platform.family()
+                || matches!(platform, Platform::Linux(Some(distro)) if distro.id == name)
+        }
+    };
+
+    hostname_matches && platform_matches
+}
+
+/// The modules a target declares with a top-level `modules = [...]` array, if any.
+pub fn target_modules(target_config: &Config) -> Option<Vec<String>> {
+    let modules = target_config.as_ref()?.get("modules")?.as_array()?;
+
+    Some(
+        modules
+            .iter()
+            .filter_map(|m| m.as_str().map(String::from))
+            .collect(),
+    )
+}
+
+/// The target config as seen by a single module: the target's `[overrides.<module>]` table, if
+/// present, is merged over the top-level target variables.
+pub fn target_config_for_module(target_config: &Config, module: &str) -> Config {
+    let mut config = target_config.clone()?;
+
+    let overrides = config.get("overrides").and_then(|o| o.get(module)).cloned();
+
+    if let Some(overrides) = overrides {
+        merge(&mut config, &overrides);
+    }
+
+    Some(config)
+}
+
+/// Deep-merges `overlay` into `base`. Tables are merged key by key; any other value in
+/// `overlay` replaces the corresponding value in `base`.
+pub fn merge(base: &mut toml::Value, overlay: &toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay.clone(),
+    }
 }
 
-#[derive(Clone, Debug)]
+/// The operating system zotfile is running on, exposed to templates as `host.platform`.
+#[derive(Clone, Debug, PartialEq)]
 pub enum Platform {
-    Linux(Distro),
-    Darwin,
+    /// Linux, with the distribution from `/etc/os-release` if there is one.
+    Linux(Option<Distro>),
+    /// macOS, with its product version (e.g. `14.4.1`).
+    Darwin(Option<String>),
+    /// FreeBSD, OpenBSD, NetBSD or DragonFly, by name (`freebsd`, ...), with the release.
+    Bsd(String, Option<String>),
     Unknown,
 }
 
-#[derive(Debug, Clone)]
+/// A Linux distribution, as described by `/etc/os-release`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Distro {
+    /// `ID`, e.g. `arch`, `manjaro`, `ubuntu`.
+    pub id: String,
+    /// `VERSION_ID`, e.g. `22.04`; rolling releases have none.
+    pub version: Option<String>,
+    /// The distribution it's derived from (the first `ID_LIKE`), or its own id, e.g. `arch`
+    /// for Manjaro and `debian` for Ubuntu.
+    pub family: String,
+    /// `PRETTY_NAME`, e.g. `Ubuntu 22.04.4 LTS`.
+    pub name: Option<String>,
+}
+
+impl Distro {
+    /// Parses the `KEY=value` lines of an os-release file. Values may be quoted.
+    pub fn from_os_release(text: &str) -> Self {
+        let mut fields = std::collections::HashMap::new();
+
+        for line in text.lines() {
+            if let Some((key, value)) = line.trim().split_once('=') {
+                let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+                fields.insert(key.trim(), value.to_owned());
+            }
+        }
+
+        let id = fields.remove("ID").unwrap_or_else(|| "linux".to_owned());
+        let family = fields
+            .get("ID_LIKE")
+            .and_then(|like| like.split_whitespace().next())
+            .unwrap_or(&id)
+            .to_owned();
+
+        Distro {
+            family,
+            version: fields.remove("VERSION_ID"),
+            name: fields.remove("PRETTY_NAME"),
+            id,
+        }
+    }
+}
+
+impl Platform {
+    /// `linux`, `macos`, `freebsd`, `openbsd`, `netbsd`, `dragonfly` or `unknown`.
+    pub fn os(&self) -> &str {
+        match self {
+            Platform::Linux(..) => "linux",
+            Platform::Darwin(..) => "macos",
+            Platform::Bsd(os, ..) => os,
+            Platform::Unknown => "unknown",
+        }
+    }
+
+    /// The Linux distribution's family (`debian`, `arch`, `rhel`...), `darwin` or `bsd`.
+    pub fn family(&self) -> Option<&str> {
+        match self {
+            Platform::Linux(distro) => distro.as_ref().map(|d| d.family.as_str()),
+            Platform::Darwin(..) => Some("darwin"),
+            Platform::Bsd(..) => Some("bsd"),
+            Platform::Unknown => None,
+        }
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        match self {
+            Platform::Linux(distro) => distro.as_ref()?.version.as_deref(),
+            Platform::Darwin(version) | Platform::Bsd(_, version) => version.as_deref(),
+            Platform::Unknown => None,
+        }
+    }
+}
+
+/// The machine zotfile is running on, exposed to templates as `host.*`.
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct HostConfig {
     username: String,
     hostname: String,
     platform: Platform,
+    #[serde(flatten)]
+    facts: Facts,
 }
 
 impl HostConfig {
-    pub fn default() -> Self {
-        HostConfig {
+    /// Detects the host the first time it's called; every module shares the result.
+    pub fn detect() -> &'static Self {
+        static HOST: OnceLock<HostConfig> = OnceLock::new();
+
+        HOST.get_or_init(|| HostConfig {
             username: util::whoami(),
             hostname: util::hostname(),
             platform: util::platform(),
-        }
+            facts: Facts::gather(),
+        })
+    }
+
+    pub fn hostname(&self) -> &str {
+        &self.hostname
     }
 }
 
+/// `host.platform.os`, `.family` and `.version` for every platform, plus `.distro` (the id)
+/// and `.name` on Linux.
 impl Serialize for Platform {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        serializer.serialize_str({
-            match self {
-                Platform::Linux(..) => "linux",
-                Platform::Darwin => "macos",
-                Platform::Unknown => "unknown",
-            }
-        })
+        let distro = match self {
+            Platform::Linux(distro) => distro.as_ref(),
+            _ => None,
+        };
+
+        let mut s = serializer.serialize_struct("Platform", 5)?;
+        s.serialize_field("os", self.os())?;
+        s.serialize_field("family", &self.family())?;
+        s.serialize_field("version", &self.version())?;
+        s.serialize_field("distro", &distro.map(|d| &d.id))?;
+        s.serialize_field("name", &distro.and_then(|d| d.name.as_ref()))?;
+        s.end()
     }
 }
 
-impl Serialize for HostConfig {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let mut s = serializer.serialize_struct("HostConfig", 2)?;
-        s.serialize_field("username", &self.username)?;
-        s.serialize_field("platform", &self.platform)?;
-        s.end()
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_target_config_for_module() {
+        let target_config = Some(
+            r#"
+            modules = ["tmux", "nvim"]
+            font_size = 12
+
+            [tmux]
+            prefix = "C-b"
+            status = "top"
+
+            [overrides.tmux]
+            font_size = 14
+            tmux = { prefix = "C-a" }
+            "#
+            .parse::<toml::Value>()
+            .unwrap(),
+        );
+
+        assert_eq!(
+            target_modules(&target_config),
+            Some(vec!["tmux".to_owned(), "nvim".to_owned()])
+        );
+
+        let tmux = target_config_for_module(&target_config, "tmux").unwrap();
+        assert_eq!(tmux["font_size"].as_integer(), Some(14));
+        assert_eq!(tmux["tmux"]["prefix"].as_str(), Some("C-a"));
+        assert_eq!(tmux["tmux"]["status"].as_str(), Some("top"));
+
+        let nvim = target_config_for_module(&target_config, "nvim").unwrap();
+        assert_eq!(nvim["font_size"].as_integer(), Some(12));
+    }
+
+    #[test]
+    fn test_parse_set() {
+        let set = parse_set(
+            vec![
+                "font_size=14",
+                "tmux.prefix=C-a",
+                "tmux.mouse=true",
+                "theme=\"dark\"",
+            ]
+            .into_iter(),
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(set["font_size"].as_integer(), Some(14));
+        assert_eq!(set["tmux"]["prefix"].as_str(), Some("C-a"));
+        assert_eq!(set["tmux"]["mouse"].as_bool(), Some(true));
+        assert_eq!(set["theme"].as_str(), Some("dark"));
+
+        assert!(parse_set(vec!["font_size"].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_os_release() {
+        let manjaro = Distro::from_os_release(
+            "NAME=\"Manjaro Linux\"\nPRETTY_NAME=\"Manjaro Linux\"\nID=manjaro\nID_LIKE=arch\n",
+        );
+        assert_eq!(
+            manjaro,
+            Distro {
+                id: "manjaro".to_owned(),
+                version: None,
+                family: "arch".to_owned(),
+                name: Some("Manjaro Linux".to_owned()),
+            }
+        );
+
+        let rocky = Distro::from_os_release(
+            "ID=\"rocky\"\nID_LIKE=\"rhel centos fedora\"\nVERSION_ID=\"9.3\"\n",
+        );
+        assert_eq!(rocky.family, "rhel");
+        assert_eq!(rocky.version.as_deref(), Some("9.3"));
+
+        assert_eq!(Distro::from_os_release("ID=fedora\n").family, "fedora");
+    }
+
+    fn load_from<'t>(targets: &'t [(&str, &str)]) -> impl Fn(&str) -> errors::Result<Config> + 't {
+        move |name| {
+            Ok(targets
+                .iter()
+                .find(|(target, _)| *target == name)
+                .map(|(_, toml)| toml.parse().unwrap()))
+        }
+    }
+
+    #[test]
+    fn test_load_with_ancestors() {
+        let targets = [
+            (
+                "laptop",
+                "extends = [\"linux\", \"gui\"]\nfont = \"laptop\"",
+            ),
+            (
+                "linux",
+                "extends = \"base\"\nshell = \"linux\"\nfont = \"linux\"",
+            ),
+            ("gui", "extends = \"base\"\nterm = \"gui\"\nfont = \"gui\""),
+            (
+                "base",
+                "shell = \"base\"\nterm = \"base\"\nfont = \"base\"\neditor = \"vi\"",
+            ),
+        ];
+        let config = load_with_ancestors("laptop", &load_from(&targets)).unwrap();
+
+        // later parents win over earlier ones, the target over its parents, and the base both
+        // parents share doesn't undo what `linux` set
+        assert_eq!(config["shell"].as_str(), Some("linux"));
+        assert_eq!(config["term"].as_str(), Some("gui"));
+        assert_eq!(config["font"].as_str(), Some("laptop"));
+        assert_eq!(config["editor"].as_str(), Some("vi"));
+        assert!(config.get("extends").is_none());
+    }
+
+    #[test]
+    fn test_load_with_ancestors_errors() {
+        let targets = [
+            ("a", "extends = \"b\""),
+            ("b", "extends = [\"c\"]"),
+            ("c", "extends = \"a\""),
+            ("d", "extends = \"missing\""),
+            ("e", "extends = [\"a\", 1]"),
+        ];
+        let error = |name| {
+            load_with_ancestors(name, &load_from(&targets))
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(error("a"), "targets extend each other: a -> b -> c -> a");
+        assert_eq!(
+            error("d"),
+            "target `missing` (extended by `d`) not found in targets/"
+        );
+        assert!(error("e").starts_with("targets/e.toml: `extends` should be"));
+    }
+
+    #[test]
+    fn test_matching_target() {
+        let rules: toml::Value = toml::from_str(
+            r#"
+            [targets.build]
+            hostname = ["build-*", "ci-??"]
+
+            [targets.laptop]
+            hostname = "Zach-MBP"
+            platform = "darwin"
+
+            [targets.arch]
+            platform = "arch"
+
+            [targets.ubuntu]
+            platform = "ubuntu"
+
+            [targets.default]
+            "#,
+        )
+        .unwrap();
+        let rules = rules["targets"].as_table().unwrap();
+
+        let manjaro = Platform::Linux(Some(Distro::from_os_release("ID=manjaro\nID_LIKE=arch\n")));
+        let ubuntu = Platform::Linux(Some(Distro::from_os_release("ID=ubuntu\nID_LIKE=debian\n")));
+        let mac = Platform::Darwin(Some("14.4".to_owned()));
+
+        // earlier rules win, even when a later one matches too
+        assert_eq!(matching_target(rules, "build-07", &manjaro), Some("build"));
+        assert_eq!(matching_target(rules, "ci-01", &mac), Some("build"));
+        // hostnames are matched case-insensitively, and platform must match as well
+        assert_eq!(matching_target(rules, "zach-mbp", &mac), Some("laptop"));
+        assert_eq!(matching_target(rules, "zach-mbp", &ubuntu), Some("ubuntu"));
+        // platform matches the family or the distro id
+        assert_eq!(matching_target(rules, "desktop", &manjaro), Some("arch"));
+        assert_eq!(matching_target(rules, "desktop", &ubuntu), Some("ubuntu"));
+        // a rule with neither hostname nor platform matches anything
+        assert_eq!(matching_target(rules, "desktop", &mac), Some("default"));
+        assert_eq!(
+            matching_target(rules, "box", &Platform::Unknown),
+            Some("default")
+        );
+
+        let mut rules = rules.clone();
+        rules.remove("default");
+        assert_eq!(matching_target(&rules, "box", &Platform::Unknown), None);
     }
 }
@@ -0,0 +1,545 @@
+use ansi_term::Colour;
+use std::ops::Range;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+    Context(String),
+    Removed(String),
+    Added(String),
+}
+
+/// A single hunk of a unified diff. Line numbers are 1-based, as in the `@@` header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    pub lines: Vec<Line>,
+}
+
+impl Hunk {
+    /// The lines this hunk expects to find in the original file (context and removals).
+    pub fn old_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                Line::Context(s) | Line::Removed(s) => Some(s.as_str()),
+                Line::Added(_) => None,
+            })
+            .collect()
+    }
+
+    /// The lines this hunk leaves in the file once applied (context and additions).
+    pub fn new_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                Line::Context(s) | Line::Added(s) => Some(s.as_str()),
+                Line::Removed(_) => None,
+            })
+            .collect()
+    }
+
+    /// Replaces the body of the hunk with `text` in unified format (as produced by `unified`),
+    /// keeping the header's start positions. Lines starting with `#` are ignored.
+    pub fn with_body(&self, text: &str) -> Self {
+        let mut lines = Vec::new();
+
+        for line in text.lines() {
+            match line.chars().next() {
+                Some('+') => lines.push(Line::Added(line[1..].to_owned())),
+                Some('-') => lines.push(Line::Removed(line[1..].to_owned())),
+                Some(' ') => lines.push(Line::Context(line[1..].to_owned())),
+                Some('#') => (),
+                // an empty line is an empty context line whose leading space was trimmed
+                None => lines.push(Line::Context(String::new())),
+                Some(_) => lines.push(Line::Context(line.to_owned())),
+            }
+        }
+
+        let mut hunk = Hunk {
+            lines,
+            ..self.clone()
+        };
+        hunk.old_len = hunk.old_lines().len();
+        hunk.new_len = hunk.new_lines().len();
+        hunk
+    }
+
+    /// The hunk body in plain unified format, without the `@@` header.
+    pub fn unified(&self) -> String {
+        let mut result = String::new();
+
+        for line in &self.lines {
+            let (prefix, s) = match line {
+                Line::Context(s) => (' ', s),
+                Line::Removed(s) => ('-', s),
+                Line::Added(s) => ('+', s),
+            };
+            result.push(prefix);
+            result.push_str(s);
+            result.push('\n');
+        }
+
+        result
+    }
+
+    fn header(&self) -> String {
+        format!(
+            "@@ -{},{} +{},{} @@",
+            self.old_start, self.old_len, self.new_start, self.new_len
+        )
+    }
+
+    /// The hunk with its `@@` header, coloured like `git diff --color` if `color` is set.
+    pub fn display(&self, color: bool) -> String {
+        let paint = |colour: Colour, s: String| {
+            if color {
+                colour.paint(s).to_string()
+            } else {
+                s
+            }
+        };
+
+        let mut result = paint(Colour::Cyan, self.header());
+        result.push('\n');
+
+        for line in &self.lines {
+            let line = match line {
+                Line::Context(s) => format!(" {}", s),
+                Line::Removed(s) => paint(Colour::Red, format!("-{}", s)),
+                Line::Added(s) => paint(Colour::Green, format!("+{}", s)),
+            };
+            result.push_str(&line);
+            result.push('\n');
+        }
+
+        result
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// An edit script entry, with the (0-based) positions in the old and new files at which it
+/// applies.
+#[derive(Debug, Clone, Copy)]
+struct Edit {
+    op: Op,
+    old: usize,
+    new: usize,
+}
+
+/// Diffs `old` against `new` line by line and groups the changes into hunks with `context`
+/// lines of unchanged text around each change, as `diff -U<context>` would.
+pub fn diff(old: &str, new: &str, context: usize) -> Vec<Hunk> {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+    let edits = edit_script(&a, &b);
+
+    let mut hunks = Vec::new();
+    let mut i = 0;
+
+    while i < edits.len() {
+        if edits[i].op == Op::Equal {
+            i += 1;
+            continue;
+        }
+
+        // extend the hunk over following changes until a run of unchanged lines is long
+        // enough to separate two hunks
+        let mut last_change = i;
+        let mut j = i;
+        while j < edits.len() {
+            if edits[j].op != Op::Equal {
+                last_change = j;
+                j += 1;
+                continue;
+            }
+
+            let run_start = j;
+            while j < edits.len() && edits[j].op == Op::Equal {
+                j += 1;
+            }
+
+            if j == edits.len() || j - run_start > 2 * context {
+                break;
+            }
+        }
+
+        let start = i.saturating_sub(context);
+        let stop = (last_change + context + 1).min(edits.len());
+        hunks.push(hunk(&edits[start..stop], &a, &b));
+        i = stop;
+    }
+
+    hunks
+}
+
+fn hunk(edits: &[Edit], a: &[&str], b: &[&str]) -> Hunk {
+    let lines: Vec<Line> = edits
+        .iter()
+        .map(|e| match e.op {
+            Op::Equal => Line::Context(a[e.old].to_owned()),
+            Op::Delete => Line::Removed(a[e.old].to_owned()),
+            Op::Insert => Line::Added(b[e.new].to_owned()),
+        })
+        .collect();
+
+    let old_len = edits.iter().filter(|e| e.op != Op::Insert).count();
+    let new_len = edits.iter().filter(|e| e.op != Op::Delete).count();
+
+    // an empty range is numbered by the line it follows
+    let first = edits[0];
+    Hunk {
+        old_start: if old_len == 0 {
+            first.old
+        } else {
+            first.old + 1
+        },
+        old_len,
+        new_start: if new_len == 0 {
+            first.new
+        } else {
+            first.new + 1
+        },
+        new_len,
+        lines,
+    }
+}
+
+/// Computes a shortest edit script from `a` to `b` with Myers' O(ND) algorithm, in its
+/// linear-space form: rather than keeping the furthest-reaching paths for every edit distance to
+/// trace the script back, it finds the middle snake of the edit graph and recurses on either
+/// side of it, so memory stays proportional to the input.
+fn edit_script(a: &[&str], b: &[&str]) -> Vec<Edit> {
+    let max = (a.len() + b.len()).div_ceil(2) + 1;
+    let mut myers = Myers {
+        a,
+        b,
+        max: max as isize,
+        forward: vec![0; 2 * max + 1],
+        backward: vec![0; 2 * max + 1],
+        edits: Vec::new(),
+    };
+    myers.conquer(0..a.len(), 0..b.len());
+    let mut edits = myers.edits;
+
+    // within a run of changes, list the deletions first, as `diff -u` does
+    let mut i = 0;
+    while i < edits.len() {
+        if edits[i].op == Op::Equal {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        let (x, y) = (edits[start].old, edits[start].new);
+        while i < edits.len() && edits[i].op != Op::Equal {
+            i += 1;
+        }
+
+        let deleted = edits[start..i]
+            .iter()
+            .filter(|e| e.op == Op::Delete)
+            .count();
+        for (j, edit) in edits[start..i].iter_mut().enumerate() {
+            *edit = if j < deleted {
+                Edit {
+                    op: Op::Delete,
+                    old: x + j,
+                    new: y,
+                }
+            } else {
+                Edit {
+                    op: Op::Insert,
+                    old: x + deleted,
+                    new: y + j - deleted,
+                }
+            };
+        }
+    }
+
+    edits
+}
+
+struct Myers<'a> {
+    a: &'a [&'a str],
+    b: &'a [&'a str],
+    max: isize,
+    // forward[k] is the furthest x reached on diagonal k (k = x - y) from the start of the
+    // range; backward[k] likewise, counting from its end
+    forward: Vec<usize>,
+    backward: Vec<usize>,
+    edits: Vec<Edit>,
+}
+
+impl<'a> Myers<'a> {
+    /// Appends the edit script for `a[a_range]` to `b[b_range]`.
+    fn conquer(&mut self, a_range: Range<usize>, b_range: Range<usize>) {
+        let (a, b) = (self.a, self.b);
+        let Range {
+            start: mut x,
+            end: mut a_end,
+        } = a_range;
+        let Range {
+            start: mut y,
+            end: mut b_end,
+        } = b_range;
+
+        while x < a_end && y < b_end && a[x] == b[y] {
+            self.edits.push(Edit {
+                op: Op::Equal,
+                old: x,
+                new: y,
+            });
+            x += 1;
+            y += 1;
+        }
+
+        let mut suffix = 0;
+        while x < a_end && y < b_end && a[a_end - 1] == b[b_end - 1] {
+            a_end -= 1;
+            b_end -= 1;
+            suffix += 1;
+        }
+
+        if x == a_end {
+            for new in y..b_end {
+                self.edits.push(Edit {
+                    op: Op::Insert,
+                    old: x,
+                    new,
+                });
+            }
+        } else if y == b_end {
+            for old in x..a_end {
+                self.edits.push(Edit {
+                    op: Op::Delete,
+                    old,
+                    new: y,
+                });
+            }
+        } else {
+            let (mid_x, mid_y) = self.middle_snake(x..a_end, y..b_end);
+            self.conquer(x..mid_x, y..mid_y);
+            self.conquer(mid_x..a_end, mid_y..b_end);
+        }
+
+        for i in 0..suffix {
+            self.edits.push(Edit {
+                op: Op::Equal,
+                old: a_end + i,
+                new: b_end + i,
+            });
+        }
+    }
+
+    /// Finds a point on a shortest path through the edit graph of `a[a_range]` and
+    /// `b[b_range]` (which differ in their first and last lines) by searching from both ends
+    /// until the paths overlap.
+    fn middle_snake(&mut self, a_range: Range<usize>, b_range: Range<usize>) -> (usize, usize) {
+        let (a, b) = (&self.a[a_range.clone()], &self.b[b_range.clone()]);
+        let (n, m) = (a.len(), b.len());
+        let delta = n as isize - m as isize;
+        let odd = delta % 2 != 0;
+        let max = self.max;
+        let at = move |k: isize| (k + max) as usize;
+
+        self.forward[at(1)] = 0;
+        self.backward[at(1)] = 0;
+
+        for d in 0..max {
+            for k in (-d..=d).rev().step_by(2) {
+                let v = &mut self.forward;
+                let mut x = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) {
+                    v[at(k + 1)]
+                } else {
+                    v[at(k - 1)] + 1
+                };
+                let (x0, y0) = (x, (x as isize - k) as usize);
+                let mut y = y0;
+
+                while x < n && y < m && a[x] == b[y] {
+                    x += 1;
+                    y += 1;
+                }
+
+                v[at(k)] = x;
+
+                if odd && (k - delta).abs() < d && x + self.backward[at(delta - k)] >= n {
+                    return (a_range.start + x0, b_range.start + y0);
+                }
+            }
+
+            for k in (-d..=d).rev().step_by(2) {
+                let v = &mut self.backward;
+                let mut x = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) {
+                    v[at(k + 1)]
+                } else {
+                    v[at(k - 1)] + 1
+                };
+                let mut y = (x as isize - k) as usize;
+
+                while x < n && y < m && a[n - x - 1] == b[m - y - 1] {
+                    x += 1;
+                    y += 1;
+                }
+
+                v[at(k)] = x;
+
+                if !odd && (k - delta).abs() <= d && x + self.forward[at(delta - k)] >= n {
+                    return (a_range.start + n - x, b_range.start + m - y);
+                }
+            }
+        }
+
+        // the paths always meet within (n + m) / 2 steps; splitting at the end is still correct
+        (a_range.end, b_range.end)
+    }
+}
+
+/// Formats hunks as a unified diff of `path`, coloured if `color` is set.
+pub fn unified(path: &str, hunks: &[Hunk], color: bool) -> String {
+    let path = path.trim_start_matches('/');
+    unified_between(&format!("a/{}", path), &format!("b/{}", path), hunks, color)
+}
+
+/// Formats hunks as a unified diff with arbitrary `---` / `+++` labels.
+pub fn unified_between(old: &str, new: &str, hunks: &[Hunk], color: bool) -> String {
+    let header = format!("--- {}\n+++ {}", old, new);
+    let mut result = if color {
+        Colour::White.bold().paint(header).to_string()
+    } else {
+        header
+    };
+    result.push('\n');
+
+    for hunk in hunks {
+        result.push_str(&hunk.display(color));
+    }
+
+    result
+}
+
+/// Applies `hunks` (sorted, non-overlapping, as produced by a single diff) to `original`.
+/// Parts of the file not covered by a hunk are copied through unchanged, so applying a subset
+/// of a diff's hunks keeps the original content for the rejected ones.
+pub fn apply(original: &str, hunks: &[Hunk]) -> String {
+    let original: Vec<&str> = original.lines().collect();
+    let mut result: Vec<&str> = Vec::new();
+    let mut cursor = 0;
+
+    for hunk in hunks {
+        // a hunk that removes nothing is positioned *after* its start line
+        let start = if hunk.old_len == 0 {
+            hunk.old_start
+        } else {
+            hunk.old_start - 1
+        };
+
+        result.extend(&original[cursor..start]);
+        result.extend(hunk.new_lines());
+        cursor = start + hunk.old_len;
+    }
+
+    result.extend(&original[cursor.min(original.len())..]);
+
+    if result.is_empty() {
+        return String::new();
+    }
+
+    let mut output = result.join("\n");
+    output.push('\n');
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ORIGINAL: &str = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+    const NEW: &str = "A\nb\nc\nd\ne\nf\ng\nh\ni\ni2\nj\n";
+
+    const DIFF: &str = "\
+--- a/file
++++ b/file
+@@ -1,4 +1,4 @@
+-a
++A
+ b
+ c
+ d
+@@ -7,4 +7,5 @@
+ g
+ h
+ i
++i2
+ j
+";
+
+    #[test]
+    fn test_diff() {
+        let hunks = diff(ORIGINAL, NEW, 3);
+
+        assert_eq!(unified("file", &hunks, false), DIFF);
+        assert_eq!(apply(ORIGINAL, &hunks), NEW);
+
+        // nearby changes share a hunk
+        assert_eq!(diff(ORIGINAL, NEW, 4).len(), 1);
+        assert!(diff(ORIGINAL, ORIGINAL, 3).is_empty());
+    }
+
+    #[test]
+    fn test_diff_insert_and_delete() {
+        assert_eq!(apply("", &diff("", ORIGINAL, 3)), ORIGINAL);
+        assert_eq!(apply(ORIGINAL, &diff(ORIGINAL, "", 3)), "");
+
+        let hunks = diff("a\nb\n", "a\nx\nb\n", 0);
+        assert_eq!(hunks.len(), 1);
+        assert_eq!((hunks[0].old_start, hunks[0].old_len), (1, 0));
+        assert_eq!(apply("a\nb\n", &hunks), "a\nx\nb\n");
+    }
+
+    #[test]
+    fn test_apply_subset() {
+        let hunks = diff(ORIGINAL, NEW, 3);
+
+        assert_eq!(
+            apply(ORIGINAL, &hunks[1..]),
+            "a\nb\nc\nd\ne\nf\ng\nh\ni\ni2\nj\n"
+        );
+        assert_eq!(apply(ORIGINAL, &[]), ORIGINAL);
+    }
+
+    #[test]
+    fn test_with_body() {
+        let hunk = &diff(ORIGINAL, NEW, 3)[0];
+        let edited = hunk.with_body("-a\n+A!\n+A2\n b\n c\n d\n");
+
+        assert_eq!(edited.old_lines(), hunk.old_lines());
+        assert_eq!(edited.new_len, 5);
+        assert!(apply(ORIGINAL, &[edited]).starts_with("A!\nA2\nb\nc\n"));
+    }
+
+    #[test]
+    fn test_diff_large() {
+        // every line differs, the worst case for the search; this used to keep a vector per
+        // edit distance and run out of memory
+        let old: String = (0..8000).map(|i| format!("old {}\n", i)).collect();
+        let new: String = (0..8000).map(|i| format!("new {}\n", i)).collect();
+
+        let hunks = diff(&old, &new, 3);
+        assert_eq!(hunks.len(), 1);
+        assert_eq!((hunks[0].old_len, hunks[0].new_len), (8000, 8000));
+        assert_eq!(apply(&old, &hunks), new);
+
+        let edited = old.replacen("old 4000\n", "changed\n", 1);
+        assert_eq!(diff(&old, &edited, 0)[0].lines.len(), 2);
+    }
+}
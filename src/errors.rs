@@ -1,11 +1,14 @@
 use std::convert::From;
+use std::fmt;
 
 #[derive(Debug)]
 pub enum ErrorKind {
-    Base,
+    Config(String),
+    DependencyCycle(Vec<String>),
     Git(String),
     Io(std::io::ErrorKind),
     Toml(Option<(usize, usize)>),
+    Write(std::path::PathBuf, std::io::ErrorKind),
 }
 
 #[derive(Debug)]
@@ -15,6 +18,32 @@ pub struct Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl Error {
+    pub fn new(kind: ErrorKind) -> Self {
+        Error { kind }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ErrorKind::Config(ref message) => write!(f, "{}", message),
+            ErrorKind::DependencyCycle(ref cycle) => {
+                write!(f, "dependency cycle: {}", cycle.join(" -> "))
+            }
+            ErrorKind::Git(ref message) => write!(f, "git error: {}", message),
+            ErrorKind::Io(kind) => write!(f, "io error: {:?}", kind),
+            ErrorKind::Toml(Some((line, col))) => {
+                write!(f, "toml error at line {}, column {}", line + 1, col + 1)
+            }
+            ErrorKind::Toml(None) => write!(f, "toml error"),
+            ErrorKind::Write(ref path, kind) => {
+                write!(f, "couldn't write {}: {}", path.display(), kind)
+            }
+        }
+    }
+}
+
 impl From<git2::Error> for self::Error {
     fn from(error: git2::Error) -> Self {
         Error {
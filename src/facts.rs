@@ -0,0 +1,141 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::util;
+
+/// What zotfile knows about the machine it's running on, besides its platform. Serialized
+/// under `host.*`, next to `host.username`, `host.hostname` and `host.platform`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Facts {
+    /// CPU architecture, as Rust names it: `x86_64`, `aarch64`...
+    pub arch: &'static str,
+    pub cores: usize,
+    /// Total memory, in MiB.
+    pub memory_mb: Option<u64>,
+    /// Kernel release, from `uname -r`.
+    pub kernel: Option<String>,
+    /// The login shell's name (`zsh`, `bash`...), from `$SHELL`.
+    pub shell: Option<String>,
+    pub home: Option<String>,
+    /// `$LC_ALL`, `$LC_CTYPE` or `$LANG`, e.g. `en_US.UTF-8`.
+    pub locale: Option<String>,
+    /// `$TERM_PROGRAM` (`iTerm.app`, `WezTerm`...), or `$TERM`.
+    pub terminal: Option<String>,
+    pub wsl: bool,
+    pub container: bool,
+    pub ssh: bool,
+    /// Every executable on `$PATH`, by name, with the path of the one that would run.
+    pub bin: BTreeMap<String, String>,
+}
+
+impl Facts {
+    pub fn gather() -> Self {
+        Facts {
+            arch: env::consts::ARCH,
+            cores: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            memory_mb: memory_mb(),
+            kernel: util::command_output("uname", &["-r"]),
+            shell: env::var("SHELL").ok().and_then(|shell| {
+                Path::new(&shell)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+            }),
+            home: dirs::home_dir().map(|home| home.to_string_lossy().into_owned()),
+            locale: ["LC_ALL", "LC_CTYPE", "LANG"]
+                .iter()
+                .find_map(|var| env::var(var).ok().filter(|v| !v.is_empty())),
+            terminal: env::var("TERM_PROGRAM").or_else(|_| env::var("TERM")).ok(),
+            wsl: env::var_os("WSL_DISTRO_NAME").is_some()
+                || fs::read_to_string("/proc/sys/kernel/osrelease")
+                    .is_ok_and(|release| release.to_lowercase().contains("microsoft")),
+            container: Path::new("/.dockerenv").exists()
+                || Path::new("/run/.containerenv").exists()
+                || env::var_os("container").is_some()
+                || fs::read_to_string("/proc/1/cgroup").is_ok_and(|c| in_container(&c)),
+            ssh: ["SSH_CONNECTION", "SSH_CLIENT", "SSH_TTY"]
+                .iter()
+                .any(|var| env::var_os(var).is_some()),
+            bin: binaries(),
+        }
+    }
+}
+
+/// Total memory, from `/proc/meminfo` on Linux or `sysctl` elsewhere.
+fn memory_mb() -> Option<u64> {
+    if let Ok(meminfo) = fs::read_to_string("/proc/meminfo") {
+        return mem_total_kb(&meminfo).map(|kb| kb / 1024);
+    }
+
+    ["hw.memsize", "hw.physmem64", "hw.physmem"]
+        .iter()
+        .find_map(|name| {
+            util::command_output("sysctl", &["-n", name])?
+                .parse::<u64>()
+                .ok()
+        })
+        .map(|bytes| bytes / 1024 / 1024)
+}
+
+fn mem_total_kb(meminfo: &str) -> Option<u64> {
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Whether PID 1's cgroups belong to a container runtime.
+fn in_container(cgroup: &str) -> bool {
+    ["docker", "kubepods", "containerd", "lxc", "libpod"]
+        .iter()
+        .any(|runtime| cgroup.contains(runtime))
+}
+
+/// The executables on `$PATH`. Earlier directories win, as they do for the shell.
+fn binaries() -> BTreeMap<String, String> {
+    let mut bin = BTreeMap::new();
+    let path = env::var_os("PATH").unwrap_or_default();
+
+    for dir in env::split_paths(&path) {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        for entry in entries.flatten() {
+            let executable = fs::metadata(entry.path())
+                .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0);
+
+            if executable {
+                bin.entry(entry.file_name().to_string_lossy().into_owned())
+                    .or_insert_with(|| entry.path().to_string_lossy().into_owned());
+            }
+        }
+    }
+
+    bin
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mem_total() {
+        let meminfo = "MemTotal:       16314244 kB\nMemFree:         8812340 kB\n";
+        assert_eq!(mem_total_kb(meminfo), Some(16_314_244));
+        assert_eq!(mem_total_kb("MemFree: 1 kB\n"), None);
+    }
+
+    #[test]
+    fn test_in_container() {
+        assert!(in_container("0::/system.slice/docker-3f2a.scope\n"));
+        assert!(!in_container("0::/init.scope\n"));
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::errors;
+use crate::manifest::{self, Manifest};
+use crate::module;
+use crate::output::Output;
+use crate::util;
+
+/// A template rendering part of a file that several modules contribute to (`fragment_of`),
+/// e.g. the nvm, fzf and golang sections of `~/.zshrc`.
+#[derive(Debug, Clone)]
+pub struct Fragment {
+    pub module: String,
+    pub source: PathBuf,
+    /// The file it's part of, with `~` expanded.
+    pub target_path: String,
+    /// Fragments of a file are sorted by `order`, then by module and template path.
+    pub order: i64,
+    pub contents: String,
+    pub attributes: util::Attributes,
+    pub warning: String,
+}
+
+/// Concatenates fragments into the files they're part of. Each file starts with the zotfile
+/// header of its first fragment, and takes that fragment's mode and ownership.
+pub fn assemble(mut fragments: Vec<Fragment>) -> Vec<Output> {
+    fragments.sort_by(|a, b| {
+        (&a.target_path, a.order, &a.module, &a.source).cmp(&(
+            &b.target_path,
+            b.order,
+            &b.module,
+            &b.source,
+        ))
+    });
+
+    let mut outputs: Vec<Output> = Vec::new();
+
+    for fragment in fragments {
+        match outputs.last_mut() {
+            Some(output) if output.target_path == fragment.target_path => {
+                output.contents.extend(fragment.contents.into_bytes());
+                if !output.contributors.contains(&fragment.module) {
+                    output.contributors.push(fragment.module);
+                }
+            }
+            _ => outputs.push(Output {
+                source: fragment.source,
+                target_path: fragment.target_path,
+                contents: format!("{}\n{}", fragment.warning, fragment.contents).into_bytes(),
+                attributes: fragment.attributes,
+                warning: Some(fragment.warning),
+                link: false,
+                block: None,
+                merge: None,
+                contributors: vec![fragment.module],
+            }),
+        }
+    }
+
+    outputs
+}
+
+/// A failed module that contributes to an assembled file, now or (per `previous`, the
+/// contributors recorded when it was last applied) before. Writing the file without that
+/// module's fragment would drop its part of the file.
+pub fn failed_contributor<'a>(
+    output: &'a Output,
+    previous: &'a [String],
+    failed: &HashSet<String>,
+) -> Option<&'a str> {
+    output
+        .contributors
+        .iter()
+        .chain(previous)
+        .find(|name| failed.contains(*name))
+        .map(String::as_str)
+}
+
+/// Manifest entries for assembled files that none of the modules that contributed to them
+/// (and still exist) has a fragment of any more. `fragments_of` renders a module's fragments.
+pub fn orphans<F>(manifest: &Manifest, fragments_of: F) -> errors::Result<Vec<manifest::Entry>>
+where
+    F: Fn(&str) -> errors::Result<Vec<Fragment>>,
+{
+    let mut orphans = Vec::new();
+
+    for entry in manifest.entries()? {
+        if entry.contributors.is_empty() {
+            continue;
+        }
+
+        let mut produced = false;
+        for name in entry
+            .contributors
+            .iter()
+            .filter(|name| module::exists(name))
+        {
+            produced = fragments_of(name)?.iter().any(|f| {
+                util::absolute_path(Path::new(&f.target_path)) == Path::new(&entry.target_path)
+            });
+            if produced {
+                break;
+            }
+        }
+
+        if !produced {
+            orphans.push(entry);
+        }
+    }
+
+    Ok(orphans)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fragment(module: &str, order: i64, contents: &str) -> Fragment {
+        Fragment {
+            module: module.to_owned(),
+            source: Path::new("modules").join(module).join("templates/zshrc"),
+            target_path: "/home/z/.zshrc".to_owned(),
+            order,
+            contents: contents.to_owned(),
+            attributes: Default::default(),
+            warning: "# zotfile\n".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_assemble() {
+        let outputs = assemble(vec![
+            fragment("fzf", 20, "source fzf.zsh\n"),
+            fragment("aliases", 90, "alias l=ls\n"),
+            fragment("nvm", 10, "source nvm.sh\n"),
+        ]);
+
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(
+            String::from_utf8(outputs[0].contents.clone()).unwrap(),
+            "# zotfile\n\nsource nvm.sh\nsource fzf.zsh\nalias l=ls\n"
+        );
+        assert_eq!(outputs[0].contributors, vec!["nvm", "fzf", "aliases"]);
+        assert_eq!(outputs[0].source, Path::new("modules/nvm/templates/zshrc"));
+    }
+
+    #[test]
+    fn test_failed_contributor() {
+        let outputs = assemble(vec![fragment("fzf", 20, ""), fragment("nvm", 10, "")]);
+        let failed = HashSet::from(["golang".to_owned()]);
+
+        assert_eq!(failed_contributor(&outputs[0], &[], &failed), None);
+        assert_eq!(
+            failed_contributor(
+                &outputs[0],
+                &["nvm".to_owned(), "golang".to_owned()],
+                &failed
+            ),
+            Some("golang")
+        );
+
+        let failed = HashSet::from(["fzf".to_owned()]);
+        assert_eq!(failed_contributor(&outputs[0], &[], &failed), Some("fzf"));
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::errors::{self, ErrorKind};
+
+/// A module in run order, along with the modules it depends on.
+#[derive(Debug, PartialEq)]
+pub struct Node {
+    pub name: String,
+    pub depends_on: Vec<String>,
+}
+
+/// Builds the dependency graph reachable from `roots` and returns every module in topological
+/// order (dependencies first). `depends_on` is called once per module to look up its direct
+/// dependencies; modules not listed in `roots` are pulled in automatically.
+///
+/// Ordering is deterministic: roots are visited in the order given and dependencies in the
+/// order they are declared.
+pub fn resolve<F>(roots: &[String], mut depends_on: F) -> errors::Result<Vec<Node>>
+where
+    F: FnMut(&str) -> errors::Result<Vec<String>>,
+{
+    let mut edges = BTreeMap::new();
+    let mut visited = HashSet::new();
+    let mut path = Vec::new();
+    let mut order = Vec::new();
+
+    for root in roots {
+        visit(
+            root,
+            &mut depends_on,
+            &mut edges,
+            &mut visited,
+            &mut path,
+            &mut order,
+        )?;
+    }
+
+    Ok(order
+        .into_iter()
+        .map(|name| {
+            let depends_on = edges.remove(&name).unwrap_or_default();
+            Node { name, depends_on }
+        })
+        .collect())
+}
+
+fn visit<F>(
+    name: &str,
+    depends_on: &mut F,
+    edges: &mut BTreeMap<String, Vec<String>>,
+    visited: &mut HashSet<String>,
+    path: &mut Vec<String>,
+    order: &mut Vec<String>,
+) -> errors::Result<()>
+where
+    F: FnMut(&str) -> errors::Result<Vec<String>>,
+{
+    if let Some(i) = path.iter().position(|n| n == name) {
+        let mut cycle = path[i..].to_vec();
+        cycle.push(name.to_owned());
+        return Err(errors::Error::new(ErrorKind::DependencyCycle(cycle)));
+    }
+
+    if visited.contains(name) {
+        return Ok(());
+    }
+
+    if !edges.contains_key(name) {
+        edges.insert(name.to_owned(), depends_on(name)?);
+    }
+
+    path.push(name.to_owned());
+    for dep in edges[name].clone() {
+        visit(&dep, depends_on, edges, visited, path, order)?;
+    }
+    path.pop();
+
+    visited.insert(name.to_owned());
+    order.push(name.to_owned());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn deps(name: &str) -> errors::Result<Vec<String>> {
+        let deps: &[&str] = match name {
+            "nvim" => &["fonts", "python-tools"],
+            "python-tools" => &["fonts"],
+            "tmux" => &["tpm"],
+            "a" => &["b"],
+            "b" => &["c"],
+            "c" => &["a"],
+            _ => &[],
+        };
+        Ok(deps.iter().map(|s| s.to_string()).collect())
+    }
+
+    fn names(nodes: Vec<Node>) -> Vec<String> {
+        nodes.into_iter().map(|n| n.name).collect()
+    }
+
+    #[test]
+    fn test_topological_order() {
+        let roots = vec!["tmux".to_owned(), "nvim".to_owned(), "fonts".to_owned()];
+        let order = names(resolve(&roots, deps).unwrap());
+
+        assert_eq!(order, vec!["tpm", "tmux", "fonts", "python-tools", "nvim"]);
+    }
+
+    #[test]
+    fn test_cycle() {
+        let err = resolve(&["a".to_owned()], deps).unwrap_err();
+        assert_eq!(err.to_string(), "dependency cycle: a -> b -> c -> a");
+    }
+}
@@ -1,4 +1,3 @@
-#![feature(slice_patterns)]
 // Top-level todos:
 // - add CLI options
 // - add callbacks/hooks to run scripts (package manager, nvim update, etc)
@@ -7,14 +6,41 @@
 // - separate config/template repository; maintain local checkout from git
 //
 
+mod backup;
+mod block;
 mod config;
+mod diff;
 mod errors;
+mod facts;
+mod fragment;
+mod graph;
+mod manifest;
+mod merge;
 mod module;
+mod options;
+mod output;
+mod patch;
+mod plan;
+mod prune;
 mod repo_config;
+mod status;
+mod summary;
 mod template;
 mod util;
 
-use crate::module::Module;
+use ansi_term::Colour;
+use std::collections::HashSet;
+use std::io::IsTerminal;
+use std::path::Path;
+
+use crate::backup::Backup;
+use crate::errors::{Error, ErrorKind};
+use crate::manifest::Manifest;
+use crate::module::{Module, Rendered};
+use crate::options::{Mode, Options};
+use crate::output::Output;
+use crate::plan::Plan;
+use crate::summary::{Outcome, Report, Summary};
 
 #[macro_use]
 extern crate clap;
@@ -24,35 +50,594 @@ fn main() {
       (version: "0.1")
       (author: "Zach Kemp <zvkemp@gmail.com>")
       (about: "Multi-target config manager")
-      (@arg TARGET: -t --target +takes_value "target config toml file")
-      (@arg MODULE: -m --module +takes_value "module to process")
-      (@arg REPODIR: -d --directory +takes_value "path to config repo (defaults to $PWD)")
+      (@arg TARGET: -t --target +takes_value +global "target config toml file (chosen by hostname from zotfile.toml if omitted)")
+      (@arg MODULE: -m --module +takes_value +multiple number_of_values(1) +global conflicts_with[ALL] "module to process (repeat for several)")
+      (@arg ALL: -a --all +global "process every module under modules/")
+      (@arg YES: -y --yes conflicts_with[DRYRUN] "apply every change without prompting")
+      (@arg FORCE: -f --force "overwrite files that were edited since they were last applied")
+      (@arg DRYRUN: -n --("dry-run") "show pending changes without writing anything")
+      (@arg COLOR: --color +takes_value +global possible_value[auto always never] "colour diffs: auto (default), always or never")
+      (@arg CONTEXT: -U --context +takes_value +global {is_number} "lines of context around each diff hunk (default 3)")
+      (@arg REPODIR: -d --directory +takes_value +global "path to config repo (defaults to $PWD)")
+      (@arg SET: -s --set +takes_value +multiple number_of_values(1) +global "set a template variable, overriding the target and frontmatter (KEY=VALUE)")
+      (@subcommand status =>
+        (about: "compare rendered templates with the files on disk")
+        (alias: "check")
+      )
+      (@subcommand rollback =>
+        (about: "restore files overwritten by a previous run")
+        (@arg RUN: --run +takes_value "backup run to restore (defaults to the most recent)")
+        (@arg LIST: -l --list conflicts_with[RUN PATH] "list backup runs")
+        (@arg PATH: "restore only this file")
+      )
+    )
+    // clap_app! only accepts identifiers as subcommand names
+    .subcommand(
+        clap::SubCommand::with_name("ls-managed")
+            .about("list the files zotfile has applied for the target"),
+    )
+    .subcommand(
+        clap::SubCommand::with_name("vars")
+            .about("print the target's variables after inheritance and overrides (for one module with -m)"),
+    )
+    .subcommand(
+        clap::SubCommand::with_name("prune")
+            .about("remove files left behind by deleted or moved templates, in every module"),
     )
     .get_matches();
 
-    let repo_dir = matches
-        .value_of("REPODIR").map(|s| s.to_owned()).unwrap_or(".".to_owned());
-
-    // FIXME : normalize this path and use for config lookup
-    let repo_path = std::path::Path::new(&repo_dir);
-
-    let args = matches.args;
-    let module = args
-        .get("MODULE")
-        .expect("please supply a module")
-        .vals
-        .get(0)
-        .unwrap();
-    let target = args
-        .get("TARGET")
-        .expect("please supply a target")
-        .vals
-        .get(0)
-        .unwrap();
-
-    let target_config = config::load_target_config(target.to_str().unwrap()).unwrap();
-    let module =
-        Module::new(module.to_str().unwrap(), target_config).expect("couldn't load module config");
-
-    dbg!(module.process()).unwrap();
+    let repo_dir = matches.value_of("REPODIR").unwrap_or(".");
+    std::env::set_current_dir(repo_dir).expect("couldn't change to config repo directory");
+
+    if let ("rollback", Some(sub)) = matches.subcommand() {
+        std::process::exit(rollback(sub));
+    }
+
+    let target = match matches.value_of("TARGET") {
+        Some(target) => target.to_owned(),
+        None => {
+            let host = config::HostConfig::detect();
+            let target = config::select_target(host).unwrap_or_else(|e| exit_with(e));
+            eprintln!(
+                "{}",
+                Colour::Cyan.paint(format!(
+                    "Using target `{}` for host `{}`",
+                    target,
+                    host.hostname()
+                ))
+            );
+            target
+        }
+    };
+    let target = target.as_str();
+
+    let set = config::parse_set(matches.values_of("SET").into_iter().flatten())
+        .unwrap_or_else(|e| exit_with(e));
+    let mut target_config = config::load_target_config(target).unwrap_or_else(|e| exit_with(e));
+    if let (Some(config), Some(set)) = (target_config.as_mut(), set.as_ref()) {
+        config::merge(config, set);
+    }
+
+    let options = Options {
+        mode: if matches.is_present("DRYRUN") {
+            Mode::DryRun
+        } else if matches.is_present("YES") {
+            Mode::Yes
+        } else {
+            Mode::Interactive
+        },
+        color: match matches.value_of("COLOR") {
+            Some("always") => true,
+            Some("never") => false,
+            _ => std::io::stdout().is_terminal(),
+        },
+        context: matches
+            .value_of("CONTEXT")
+            .map_or(3, |n| n.parse().unwrap()),
+        force: matches.is_present("FORCE"),
+        set,
+    };
+
+    let backup = Backup::new();
+    let manifest = Manifest::for_target(target);
+
+    if matches.subcommand_name() == Some("ls-managed") {
+        std::process::exit(ls_managed(&manifest));
+    }
+
+    if matches.subcommand_name() == Some("vars") {
+        std::process::exit(vars(
+            &target_config,
+            matches.values_of("MODULE"),
+            &options,
+            &backup,
+            &manifest,
+        ));
+    }
+
+    if matches.subcommand_name() == Some("prune") {
+        std::process::exit(prune(&target_config, &options, &backup, &manifest));
+    }
+
+    let modules: Vec<String> = if matches.is_present("ALL") {
+        module::discover().expect("couldn't read modules directory")
+    } else if let Some(modules) = matches.values_of("MODULE") {
+        modules.map(String::from).collect()
+    } else {
+        config::target_modules(&target_config).unwrap_or_else(|| {
+            panic!(
+                "please supply a module (or --all), or list `modules` in targets/{}.toml",
+                target
+            )
+        })
+    };
+
+    let nodes = graph::resolve(&modules, |name| {
+        if !module::exists(name) {
+            return Err(Error::new(ErrorKind::Config(format!(
+                "module `{}` not found in modules/",
+                name
+            ))));
+        }
+
+        let module_target_config = config::target_config_for_module(&target_config, name);
+        Module::new(name, module_target_config, &options, &backup, &manifest)?.depends_on()
+    })
+    .unwrap_or_else(|e| exit_with(e));
+
+    let code = match matches.subcommand_name() {
+        Some("status") => status(&nodes, &target_config, &options, &backup, &manifest),
+        _ => apply(&nodes, &target_config, &options, &backup, &manifest),
+    };
+
+    std::process::exit(code);
+}
+
+fn exit_with(e: Error) -> ! {
+    eprintln!("{} {}", Colour::Red.bold().paint("error:"), e);
+    std::process::exit(1);
+}
+
+fn is_number(value: String) -> Result<(), String> {
+    value
+        .parse::<usize>()
+        .map(|_| ())
+        .map_err(|_| format!("`{}` is not a number", value))
+}
+
+// Exit codes follow `terraform plan -detailed-exitcode`: 1 for failures, 2 when a dry run (or
+// `status`) found pending changes.
+fn apply(
+    nodes: &[graph::Node],
+    target_config: &config::Config,
+    options: &Options,
+    backup: &Backup,
+    manifest: &Manifest,
+) -> i32 {
+    let mut summary = Summary::default();
+    let mut failed = HashSet::new();
+
+    let plan = Plan::new(nodes, target_config, options, backup, manifest);
+    if report_collisions(&plan) {
+        return 1;
+    }
+
+    let Plan {
+        mut modules,
+        assembled,
+        failures,
+    } = plan;
+
+    for (name, e) in failures {
+        summary.record(&name, Err(e));
+        failed.insert(name);
+    }
+
+    for node in nodes {
+        let name = &node.name;
+        // already recorded as failed
+        if failed.contains(name) {
+            continue;
+        }
+
+        let result = match node.depends_on.iter().find(|dep| failed.contains(*dep)) {
+            Some(dep) => Err(Error::new(ErrorKind::Config(format!(
+                "skipped because dependency `{}` failed",
+                dep
+            )))),
+            None => {
+                let rendered = rendered_by(&mut modules, name).expect("module was planned");
+                let module_target_config = config::target_config_for_module(target_config, name);
+                Module::new(name, module_target_config, options, backup, manifest)
+                    .and_then(|module| module.process(rendered))
+            }
+        };
+
+        if result.is_err() {
+            failed.insert(name.clone());
+        }
+
+        summary.record(name, result);
+    }
+
+    for output in &assembled {
+        let previous = match manifest.get(output.path(), None) {
+            Ok(entry) => entry.map(|e| e.contributors).unwrap_or_default(),
+            Err(e) => {
+                summary.record(&output.target_path, Err(e));
+                continue;
+            }
+        };
+
+        // writing the file without a failed module's fragment would drop its part of it
+        let result = match fragment::failed_contributor(output, &previous, &failed) {
+            Some(name) => {
+                println!(
+                    "{} {}",
+                    Colour::Green.bold().paint(&output.target_path),
+                    Colour::Yellow.paint(format!("skipped because `{}` failed.", name))
+                );
+                let mut report = Report::default();
+                report.record(Outcome::Skipped);
+                Ok(report)
+            }
+            None => process_assembled(output, target_config, options, backup, manifest),
+        };
+
+        summary.record(&output.target_path, result);
+    }
+
+    prune_fragments(
+        target_config,
+        options,
+        backup,
+        manifest,
+        &modules,
+        &mut summary,
+    );
+
+    finish(&summary, backup, options)
+}
+
+/// What `name` rendered for the plan, if it was planned.
+fn rendered_by<'p>(modules: &'p mut [(String, Rendered)], name: &str) -> Option<&'p mut Rendered> {
+    modules
+        .iter_mut()
+        .find(|(module, _)| module == name)
+        .map(|(_, rendered)| rendered)
+}
+
+/// Prints every collision in the plan, returning whether there were any.
+fn report_collisions(plan: &Plan) -> bool {
+    let collisions = plan.collisions();
+
+    for collision in &collisions {
+        eprintln!("{} {}", Colour::Red.bold().paint("error:"), collision);
+    }
+
+    !collisions.is_empty()
+}
+
+/// Applies a file assembled from fragments, on behalf of the module whose fragment comes first.
+fn process_assembled(
+    output: &Output,
+    target_config: &config::Config,
+    options: &Options,
+    backup: &Backup,
+    manifest: &Manifest,
+) -> errors::Result<Report> {
+    let name = &output.contributors[0];
+    let module_target_config = config::target_config_for_module(target_config, name);
+    let module = Module::new(name, module_target_config, options, backup, manifest)?;
+
+    let mut report = Report::default();
+    report.record(module.process_output(output)?);
+    Ok(report)
+}
+
+/// Files assembled from fragments that no module contributes to any more. Modules in `planned`
+/// aren't rendered again; any other contributor is.
+fn fragment_orphans(
+    target_config: &config::Config,
+    options: &Options,
+    backup: &Backup,
+    manifest: &Manifest,
+    planned: &[(String, Rendered)],
+) -> errors::Result<Vec<manifest::Entry>> {
+    fragment::orphans(manifest, |name| {
+        if let Some((_, rendered)) = planned.iter().find(|(module, _)| module == name) {
+            return Ok(rendered.fragments.clone());
+        }
+
+        let module_target_config = config::target_config_for_module(target_config, name);
+        Ok(
+            Module::new(name, module_target_config, options, backup, manifest)?
+                .render()?
+                .fragments,
+        )
+    })
+}
+
+/// Offers to remove files assembled from fragments that no module contributes to any more.
+fn prune_fragments(
+    target_config: &config::Config,
+    options: &Options,
+    backup: &Backup,
+    manifest: &Manifest,
+    planned: &[(String, Rendered)],
+    summary: &mut Summary,
+) {
+    match fragment_orphans(target_config, options, backup, manifest, planned) {
+        Ok(orphans) if orphans.is_empty() => (),
+        result => summary.record(
+            "fragments",
+            result.and_then(|orphans| prune::remove(&orphans, options, backup, manifest)),
+        ),
+    }
+}
+
+/// Prunes orphaned files from every module in the manifest, including modules that have since
+/// been deleted from `modules/` (all of whose files are orphans).
+fn prune(
+    target_config: &config::Config,
+    options: &Options,
+    backup: &Backup,
+    manifest: &Manifest,
+) -> i32 {
+    let entries = match manifest.entries() {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("{} {}", Colour::Red.bold().paint("error:"), e);
+            return 1;
+        }
+    };
+
+    let mut names: Vec<&str> = entries.iter().map(|e| e.module.as_str()).collect();
+    names.dedup();
+
+    let mut summary = Summary::default();
+
+    for name in names {
+        let result = if module::exists(name) {
+            let module_target_config = config::target_config_for_module(target_config, name);
+            Module::new(name, module_target_config, options, backup, manifest)
+                .and_then(|module| module.prune(&module.render()?))
+        } else {
+            let orphans: Vec<_> = entries
+                .iter()
+                .filter(|e| e.module == name && e.contributors.is_empty())
+                .cloned()
+                .collect();
+            prune::remove(&orphans, options, backup, manifest)
+        };
+
+        summary.record(name, result);
+    }
+
+    prune_fragments(target_config, options, backup, manifest, &[], &mut summary);
+
+    finish(&summary, backup, options)
+}
+
+/// Prints the run summary and returns the exit code for it.
+fn finish(summary: &Summary, backup: &Backup, options: &Options) -> i32 {
+    summary.print(options.color);
+
+    if backup.entries().is_ok_and(|e| !e.is_empty()) {
+        println!(
+            "\nPrevious versions were backed up; undo with `zotfile rollback --run {}`",
+            backup.id()
+        );
+    }
+
+    if summary.has_failures() {
+        1
+    } else if summary.has_pending() {
+        2
+    } else {
+        0
+    }
+}
+
+fn status(
+    nodes: &[graph::Node],
+    target_config: &config::Config,
+    options: &Options,
+    backup: &Backup,
+    manifest: &Manifest,
+) -> i32 {
+    let mut entries = Vec::new();
+    let mut code = 0;
+
+    let plan = Plan::new(nodes, target_config, options, backup, manifest);
+    if report_collisions(&plan) {
+        code = 1;
+    }
+
+    for (name, e) in &plan.failures {
+        eprintln!(
+            "{} {}",
+            util::paint(
+                Colour::Red.bold(),
+                &format!("{} failed:", name),
+                options.color
+            ),
+            e
+        );
+        code = 1;
+    }
+
+    for (name, rendered) in &plan.modules {
+        let module_target_config = config::target_config_for_module(target_config, name);
+        match Module::new(name, module_target_config, options, backup, manifest)
+            .and_then(|m| m.status(rendered))
+        {
+            Ok(mut e) => entries.append(&mut e),
+            Err(e) => {
+                eprintln!(
+                    "{} {}",
+                    util::paint(
+                        Colour::Red.bold(),
+                        &format!("{} failed:", name),
+                        options.color
+                    ),
+                    e
+                );
+                code = 1;
+            }
+        }
+    }
+
+    let assembled = plan
+        .assembled
+        .iter()
+        .map(|output| {
+            Ok(status::Entry {
+                module: output.contributors.join(", "),
+                target_path: output.target_path.clone(),
+                status: status::check(output, manifest.get(output.path(), None)?.as_ref())?,
+            })
+        })
+        .collect::<errors::Result<Vec<_>>>()
+        .and_then(|mut assembled| {
+            for orphan in fragment_orphans(target_config, options, backup, manifest, &plan.modules)?
+            {
+                if Path::new(&orphan.target_path).is_file() {
+                    assembled.push(status::Entry {
+                        module: orphan.contributors.join(", "),
+                        target_path: orphan.target_path,
+                        status: status::FileStatus::Orphaned,
+                    });
+                }
+            }
+            Ok(assembled)
+        });
+
+    match assembled {
+        Ok(mut assembled) => entries.append(&mut assembled),
+        Err(e) => {
+            eprintln!(
+                "{} {}",
+                util::paint(Colour::Red.bold(), "fragments failed:", options.color),
+                e
+            );
+            code = 1;
+        }
+    }
+
+    status::print(&entries, options.color);
+
+    if code == 0 && entries.iter().any(|e| e.status.is_drift()) {
+        code = 2;
+    }
+
+    code
+}
+
+fn rollback(matches: &clap::ArgMatches) -> i32 {
+    if matches.is_present("LIST") {
+        for id in backup::runs().unwrap() {
+            let count = Backup::open(Some(&id))
+                .and_then(|b| b.entries())
+                .map_or(0, |e| e.len());
+            println!("  {}  {} file(s)", Colour::Cyan.bold().paint(id), count);
+        }
+        return 0;
+    }
+
+    let undo = Backup::new();
+    let result = Backup::open(matches.value_of("RUN")).and_then(|run| {
+        let restored = run.restore(matches.value_of("PATH").map(Path::new), &undo)?;
+        println!(
+            "{}",
+            Colour::Green.paint(format!(
+                "Restored {} file(s) from run {}",
+                restored,
+                run.id()
+            ))
+        );
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{} {}", Colour::Red.bold().paint("error:"), e);
+            1
+        }
+    }
+}
+
+/// Prints the variables templates see under `target`: the target after everything it inherits,
+/// with a module's overrides and its config's `vars` if one is given, and `--set` over all of
+/// it. Frontmatter `vars` are per template, so they aren't included.
+fn vars(
+    target_config: &config::Config,
+    modules: Option<clap::Values>,
+    options: &Options,
+    backup: &Backup,
+    manifest: &Manifest,
+) -> i32 {
+    let modules: Vec<&str> = modules.map(|m| m.collect()).unwrap_or_default();
+
+    let mut config = match modules.as_slice() {
+        [] => target_config.clone(),
+        [module] => {
+            let module_target_config = config::target_config_for_module(target_config, module);
+            match Module::new(module, module_target_config, options, backup, manifest) {
+                Ok(module) => module.target_config().clone(),
+                Err(e) => {
+                    eprintln!("{} {}", Colour::Red.bold().paint("error:"), e);
+                    return 1;
+                }
+            }
+        }
+        _ => {
+            eprintln!(
+                "{} vars takes at most one module",
+                Colour::Red.bold().paint("error:")
+            );
+            return 1;
+        }
+    };
+
+    // --set wins over the module's overrides and vars, too
+    if let (Some(config), Some(set)) = (config.as_mut(), options.set.as_ref()) {
+        config::merge(config, set);
+    }
+
+    match config.map(|c| toml::to_string_pretty(&c)) {
+        Some(Ok(toml)) => print!("{}", toml),
+        Some(Err(e)) => {
+            eprintln!("{} {}", Colour::Red.bold().paint("error:"), e);
+            return 1;
+        }
+        None => (),
+    }
+
+    0
+}
+
+fn ls_managed(manifest: &Manifest) -> i32 {
+    let entries = match manifest.entries() {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("{} {}", Colour::Red.bold().paint("error:"), e);
+            return 1;
+        }
+    };
+
+    let width = entries.iter().map(|e| e.module.len()).max().unwrap_or(0);
+
+    for entry in entries {
+        println!(
+            "  {}  {}  {}  {}",
+            Colour::Cyan.paint(format!("{:width$}", entry.module, width = width)),
+            entry.applied_at,
+            Colour::Green.bold().paint(entry.target_path),
+            entry.source
+        );
+    }
+
+    0
 }
@@ -0,0 +1,219 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::block::Markers;
+use crate::errors;
+use crate::merge::Format;
+use crate::output::Output;
+use crate::util;
+
+/// A file zotfile wrote, as of the last time it was applied.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub module: String,
+    /// The template (or other source file) in the config repo the file was rendered from.
+    pub source: String,
+    /// Absolute path of the written file.
+    pub target_path: String,
+    /// SHA-256 of the contents written, as hex.
+    pub hash: String,
+    pub applied_at: String,
+    /// For a managed block, its markers; the hash then covers only the block.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block: Option<Markers>,
+    /// For a structured merge, the file's format; the hash then covers only the template's
+    /// keys, which are kept (as JSON) with the applied contents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merge: Option<Format>,
+    /// For a file assembled from fragments, the modules that contributed them. It's orphaned
+    /// once none of them does, whichever module it's recorded under.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub contributors: Vec<String>,
+    /// Whether zotfile installed a symlink here; the hash then covers the link's destination.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub link: bool,
+}
+
+impl Entry {
+    fn is(&self, target_path: &str, block: Option<&Markers>) -> bool {
+        self.target_path == target_path && self.block.as_ref() == block
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Contents {
+    #[serde(default)]
+    files: Vec<Entry>,
+}
+
+/// The applied-state manifest for a target, stored at
+/// `<state dir>/manifests/<target>.toml`. Every method reads or writes the file directly, so the
+/// manifest is always up to date on disk, even if a run is interrupted.
+///
+/// The contents of every applied file are also kept, by hash, under `<state dir>/objects/`, so
+/// that local edits can be shown against what zotfile last wrote.
+#[derive(Debug)]
+pub struct Manifest {
+    path: PathBuf,
+}
+
+impl Manifest {
+    pub fn for_target(target: &str) -> Self {
+        Manifest {
+            path: util::state_dir()
+                .join("manifests")
+                .join(format!("{}.toml", target)),
+        }
+    }
+
+    /// Every managed file, sorted by module and then by target path.
+    pub fn entries(&self) -> errors::Result<Vec<Entry>> {
+        if !self.path.is_file() {
+            return Ok(vec![]);
+        }
+
+        let contents: Contents = toml::from_str(&util::read_file_to_string(&self.path)?)?;
+        Ok(contents.files)
+    }
+
+    /// The entry for the whole file at `target_path`, or for one block in it.
+    pub fn get(
+        &self,
+        target_path: &Path,
+        block: Option<&Markers>,
+    ) -> errors::Result<Option<Entry>> {
+        let target_path = util::absolute_path(target_path);
+        let target_path = target_path.to_string_lossy();
+
+        Ok(self
+            .entries()?
+            .into_iter()
+            .find(|e| e.is(&target_path, block)))
+    }
+
+    /// Records that `contents` (the managed part of `output`) was written to its target path,
+    /// replacing any previous entry for it.
+    pub fn record(&self, module: &str, output: &Output, contents: &[u8]) -> errors::Result<()> {
+        let block = output.block.as_ref();
+        let object = objects_dir().join(hash(contents));
+        if !object.is_file() {
+            util::create_private_dir(&objects_dir())?;
+            let attributes = util::Attributes {
+                mode: Some(0o600),
+                ..Default::default()
+            };
+            util::write_atomic(&object, contents, &attributes)?;
+        }
+
+        let entry = Entry {
+            module: module.to_owned(),
+            source: output.source.to_string_lossy().into_owned(),
+            target_path: util::absolute_path(output.path())
+                .to_string_lossy()
+                .into_owned(),
+            hash: hash(contents),
+            applied_at: util::timestamp(),
+            block: block.cloned(),
+            merge: output.merge.as_ref().map(|m| m.format),
+            contributors: output.contributors.clone(),
+            link: output.link,
+        };
+
+        let mut files = self.entries()?;
+        files.retain(|e| !e.is(&entry.target_path, block));
+        files.push(entry);
+        files.sort_by(|a, b| (&a.module, &a.target_path).cmp(&(&b.module, &b.target_path)));
+
+        self.write(Contents { files })
+    }
+
+    /// Drops the entry for `target_path` (or a block in it), once zotfile no longer manages it.
+    pub fn forget(&self, target_path: &Path, block: Option<&Markers>) -> errors::Result<()> {
+        let target_path = util::absolute_path(target_path);
+        let target_path = target_path.to_string_lossy();
+
+        let mut files = self.entries()?;
+        files.retain(|e| !e.is(&target_path, block));
+
+        self.write(Contents { files })
+    }
+
+    /// Every file applied for `module`.
+    pub fn module_entries(&self, module: &str) -> errors::Result<Vec<Entry>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .filter(|e| e.module == module)
+            .collect())
+    }
+
+    /// The contents last applied for an entry, if they were kept.
+    pub fn applied_contents(&self, entry: &Entry) -> Option<String> {
+        std::fs::read_to_string(objects_dir().join(&entry.hash)).ok()
+    }
+
+    fn write(&self, contents: Contents) -> errors::Result<()> {
+        let toml = toml::to_string(&contents).expect("manifest serializes");
+        util::create_private_dir(self.path.parent().expect("manifests dir"))?;
+        util::write_atomic(&self.path, toml.as_bytes(), &Default::default())
+    }
+}
+
+fn objects_dir() -> PathBuf {
+    util::state_dir().join("objects")
+}
+
+/// The hash of what's at `path`, as the manifest records it: for a symlink zotfile installed
+/// (`link`), the link's destination; otherwise the contents, following any symlink.
+pub fn hash_on_disk(path: &Path, link: bool) -> std::io::Result<String> {
+    let metadata = std::fs::symlink_metadata(path)?;
+
+    if link && metadata.file_type().is_symlink() {
+        Ok(hash(std::fs::read_link(path)?.as_os_str().as_bytes()))
+    } else {
+        Ok(hash(&std::fs::read(path)?))
+    }
+}
+
+/// SHA-256 of `contents`, as lowercase hex.
+pub fn hash(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hash() {
+        assert_eq!(
+            hash(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_hash_on_disk() {
+        let tmp = std::env::temp_dir().join(format!("zotfile-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(&tmp).unwrap();
+        let file = tmp.join("stow-rc");
+        let link = tmp.join("rc");
+        std::fs::write(&file, "set nu\n").unwrap();
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+
+        // a written file behind a stow link is hashed by its contents; a link zotfile
+        // installed, by its destination
+        assert_eq!(hash_on_disk(&link, false).unwrap(), hash(b"set nu\n"));
+        assert_eq!(
+            hash_on_disk(&link, true).unwrap(),
+            hash(file.as_os_str().as_bytes())
+        );
+
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+}
@@ -0,0 +1,370 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::path::Path;
+
+use crate::errors::{self, Error, ErrorKind};
+
+/// A structured file format zotfile can merge into (`strategy = "merge"`).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+    Ini,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" | "jsonc" => Some(Format::Json),
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "ini" | "cfg" | "conf" | "gitconfig" => Some(Format::Ini),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format from a file extension.
+    pub fn for_path(path: &Path) -> Option<Self> {
+        Self::from_name(path.extension()?.to_str()?)
+    }
+
+    /// Parses `text` into a tree of values. An empty file is an empty table.
+    pub fn parse(self, text: &str) -> errors::Result<Value> {
+        if text.trim().is_empty() {
+            return Ok(Value::Object(Map::new()));
+        }
+
+        let parse_error = |e: String| Error::new(ErrorKind::Config(format!("{:?}: {}", self, e)));
+
+        match self {
+            Format::Json => {
+                serde_json::from_str(&strip_jsonc(text)).map_err(|e| parse_error(e.to_string()))
+            }
+            Format::Toml => {
+                let value: toml::Value = text.parse()?;
+                serde_json::to_value(value).map_err(|e| parse_error(e.to_string()))
+            }
+            Format::Yaml => serde_yaml::from_str(text).map_err(|e| parse_error(e.to_string())),
+            Format::Ini => Ok(parse_ini(text)),
+        }
+    }
+
+    pub fn serialize(self, value: &Value) -> errors::Result<String> {
+        let serialize_error =
+            |e: String| Error::new(ErrorKind::Config(format!("{:?}: {}", self, e)));
+
+        let mut text = match self {
+            Format::Json => {
+                serde_json::to_string_pretty(value).map_err(|e| serialize_error(e.to_string()))?
+            }
+            Format::Toml => {
+                let value: toml::Value = serde_json::from_value(value.clone())
+                    .map_err(|e| serialize_error(e.to_string()))?;
+                toml::to_string_pretty(&value).map_err(|e| serialize_error(e.to_string()))?
+            }
+            Format::Yaml => {
+                serde_yaml::to_string(value).map_err(|e| serialize_error(e.to_string()))?
+            }
+            Format::Ini => serialize_ini(value),
+        };
+
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        Ok(text)
+    }
+}
+
+/// A template merged into a structured file: zotfile owns the template's keys and nothing else.
+#[derive(Debug)]
+pub struct Merge {
+    pub format: Format,
+    pub template: Value,
+}
+
+impl Merge {
+    /// The keys of `contents` the template owns, as pretty-printed JSON, or `None` if
+    /// `contents` doesn't parse.
+    pub fn managed(&self, contents: &[u8]) -> Option<Vec<u8>> {
+        let value = self
+            .format
+            .parse(std::str::from_utf8(contents).ok()?)
+            .ok()?;
+        Some(owned_keys(&value, &self.template))
+    }
+}
+
+/// `value` projected onto the keys of `shape`, as recorded in the manifest.
+pub fn owned_keys(value: &Value, shape: &Value) -> Vec<u8> {
+    let mut json = serde_json::to_string_pretty(&project(value, shape)).expect("json serializes");
+    json.push('\n');
+    json.into_bytes()
+}
+
+/// Deep-merges `over` into `base`: tables are merged key by key, and anything else in `over`
+/// (including arrays) replaces what's in `base`.
+pub fn merge(base: &mut Value, over: &Value) {
+    match (base, over) {
+        (Value::Object(base), Value::Object(over)) => {
+            for (key, value) in over {
+                match base.get_mut(key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (base, over) => *base = over.clone(),
+    }
+}
+
+/// The part of `value` at the keys of `shape`: what a merge of `shape` owns.
+pub fn project(value: &Value, shape: &Value) -> Value {
+    match (value, shape) {
+        (Value::Object(value), Value::Object(shape)) => Value::Object(
+            shape
+                .iter()
+                .filter_map(|(key, shape)| Some((key.clone(), project(value.get(key)?, shape))))
+                .collect(),
+        ),
+        (value, _) => value.clone(),
+    }
+}
+
+/// Removes the keys of `shape` from `value`, and any tables left empty by that.
+pub fn remove(value: &mut Value, shape: &Value) {
+    if let (Value::Object(value), Value::Object(shape)) = (value, shape) {
+        for (key, shape) in shape {
+            let emptied = match value.get_mut(key) {
+                Some(child @ Value::Object(_)) if shape.is_object() => {
+                    remove(child, shape);
+                    child.as_object().is_some_and(|c| c.is_empty())
+                }
+                Some(_) => true,
+                None => false,
+            };
+
+            if emptied {
+                value.remove(key);
+            }
+        }
+    }
+}
+
+/// Strips the comments and trailing commas JSONC allows (VS Code's `settings.json`, for one), so
+/// the rest parses as plain JSON. Strings are left alone.
+fn strip_jsonc(text: &str) -> String {
+    let mut json = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                json.push(c);
+                while let Some(c) = chars.next() {
+                    json.push(c);
+                    match c {
+                        '\\' => json.extend(chars.next()),
+                        '"' => break,
+                        _ => (),
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => while chars.next_if(|&c| c != '\n').is_some() {},
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                for c in chars.by_ref() {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+                json.push(' ');
+            }
+            '}' | ']' => {
+                // drop a trailing comma, and whatever whitespace and comments followed it
+                let end = json.trim_end().len();
+                if json[..end].ends_with(',') {
+                    json.truncate(end - 1);
+                }
+                json.push(c);
+            }
+            c => json.push(c),
+        }
+    }
+
+    json
+}
+
+/// A minimal INI reader: `key = value` lines, optionally under `[section]` headers, with `;`
+/// and `#` comments. Keys before the first section go at the top level. A key that appears more
+/// than once (as gitconfig allows) is read as an array of its values, and a bare `key` with no
+/// `=` as null, so both survive being written back.
+fn parse_ini(text: &str) -> Value {
+    let mut root = Map::new();
+    let mut section: Option<String> = None;
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            let name = line[1..line.len() - 1].trim().to_owned();
+            root.entry(name.clone())
+                .or_insert_with(|| Value::Object(Map::new()));
+            section = Some(name);
+            continue;
+        }
+
+        let (key, value) = match line.find('=') {
+            Some(i) => (
+                line[..i].trim(),
+                Value::String(line[i + 1..].trim().to_owned()),
+            ),
+            None => (line, Value::Null),
+        };
+
+        match section {
+            Some(ref name) => {
+                if let Some(Value::Object(table)) = root.get_mut(name) {
+                    insert_ini(table, key, value);
+                }
+            }
+            None => insert_ini(&mut root, key, value),
+        }
+    }
+
+    Value::Object(root)
+}
+
+fn insert_ini(table: &mut Map<String, Value>, key: &str, value: Value) {
+    match table.get_mut(key) {
+        Some(Value::Array(values)) => values.push(value),
+        Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+        None => {
+            table.insert(key.to_owned(), value);
+        }
+    }
+}
+
+/// Writes `key = value`, a bare `key` for null, or a line per value for an array.
+fn push_ini(text: &mut String, key: &str, value: &Value) {
+    match value {
+        Value::Null => text.push_str(&format!("{}\n", key)),
+        Value::String(s) => text.push_str(&format!("{} = {}\n", key, s)),
+        Value::Array(values) => {
+            for value in values {
+                push_ini(text, key, value);
+            }
+        }
+        value => text.push_str(&format!("{} = {}\n", key, value)),
+    }
+}
+
+fn serialize_ini(value: &Value) -> String {
+    let mut text = String::new();
+    let mut sections = String::new();
+
+    if let Value::Object(root) = value {
+        for (key, value) in root {
+            match value {
+                Value::Object(table) => {
+                    sections.push_str(&format!("\n[{}]\n", key));
+                    for (key, value) in table {
+                        push_ini(&mut sections, key, value);
+                    }
+                }
+                value => push_ini(&mut text, key, value),
+            }
+        }
+    }
+
+    text.push_str(&sections);
+    text.trim_start_matches('\n').to_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_merge() {
+        let mut base =
+            json!({"editor": {"fontSize": 12, "tabSize": 4}, "theme": "dark", "list": [1, 2]});
+        let over = json!({"editor": {"fontSize": 14}, "list": [3]});
+
+        merge(&mut base, &over);
+        assert_eq!(
+            base,
+            json!({"editor": {"fontSize": 14, "tabSize": 4}, "theme": "dark", "list": [3]})
+        );
+
+        assert_eq!(project(&base, &over), over);
+
+        remove(&mut base, &over);
+        assert_eq!(base, json!({"editor": {"tabSize": 4}, "theme": "dark"}));
+    }
+
+    #[test]
+    fn test_ini() {
+        let text = "; comment\nname = x\n\n[user]\nemail = a@b.c\n[core]\neditor=vim\n";
+        let value = Format::Ini.parse(text).unwrap();
+
+        assert_eq!(
+            value,
+            json!({"name": "x", "user": {"email": "a@b.c"}, "core": {"editor": "vim"}})
+        );
+        assert_eq!(
+            Format::Ini.serialize(&value).unwrap(),
+            "name = x\n\n[user]\nemail = a@b.c\n\n[core]\neditor = vim\n"
+        );
+
+        // repeated keys and bare keys, as in a gitconfig, are kept
+        let text =
+            "[include]\npath = a\npath = b\n[remote \"origin\"]\nfetch = x\nfetch = y\nbare\n";
+        let mut value = Format::Ini.parse(text).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "include": {"path": ["a", "b"]},
+                "remote \"origin\"": {"fetch": ["x", "y"], "bare": null}
+            })
+        );
+
+        merge(&mut value, &json!({"include": {"other": "c"}}));
+        assert_eq!(
+            Format::Ini.serialize(&value).unwrap(),
+            "[include]\npath = a\npath = b\nother = c\n\n\
+             [remote \"origin\"]\nfetch = x\nfetch = y\nbare\n"
+        );
+    }
+
+    #[test]
+    fn test_jsonc() {
+        let text = r#"{
+            // editor
+            "editor.fontSize": 14, /* px */
+            "files.exclude": {"**/.git": true,},
+            "url": "https://example.com/*not a comment*/",
+            "list": [1, 2, ],
+        }"#;
+
+        assert_eq!(
+            Format::Json.parse(text).unwrap(),
+            json!({
+                "editor.fontSize": 14,
+                "files.exclude": {"**/.git": true},
+                "url": "https://example.com/*not a comment*/",
+                "list": [1, 2]
+            })
+        );
+        assert!(Format::Json.parse("{\"a\": 1 \"b\": 2}").is_err());
+    }
+}
@@ -1,22 +1,49 @@
 use ansi_term::Colour;
-use std::error::Error;
-use std::fs::{self, File};
+use std::fs;
 use std::io::{self, Write};
-use std::path::Path;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 
 use serde::Deserialize;
 
-use crate::config::{Config, HostConfig};
-use crate::errors;
+use crate::backup::Backup;
+use crate::block::Markers;
+use crate::config::{self, Config, HostConfig};
+use crate::diff::{self, Hunk};
+use crate::errors::{self, Error, ErrorKind};
+use crate::fragment::Fragment;
+use crate::manifest::{self, Manifest};
+use crate::merge;
+use crate::options::{Mode, Options};
+use crate::output::{LinkState, Output};
+use crate::patch;
+use crate::prune;
 use crate::repo_config::RepoConfig;
+use crate::status;
+use crate::summary::{Outcome, Report};
 use crate::template::Template;
+use crate::util;
+
+/// Everything a module renders for a run: its own outputs, and its fragments of files shared
+/// with other modules. Rendered once per run (see `Plan`), then processed.
+#[derive(Debug, Default)]
+pub struct Rendered {
+    pub outputs: Vec<Output>,
+    pub fragments: Vec<Fragment>,
+}
 
 #[derive(Debug)]
 pub struct Module<'a> {
     name: &'a str,
     target_config: Config,
-    host_config: HostConfig,
+    host_config: &'a HostConfig,
     module_config: Config,
+    /// The module config as templates see it, under `module.*` (see `template_vars`).
+    module_vars: Config,
+    options: &'a Options,
+    backup: &'a Backup,
+    manifest: &'a Manifest,
 }
 
 #[derive(Debug, Deserialize)]
@@ -28,44 +55,73 @@ use std::process::Command;
 use std::process::Stdio;
 
 impl AfterCommitHook {
+    pub fn dry_run(&self) {
+        if let Some(ref command) = self.shell {
+            println!(
+                "{}",
+                Colour::Yellow.paint(format!("Would run `{}`", command))
+            );
+        }
+    }
+
     pub fn process(&self) -> errors::Result<()> {
-        match self.shell {
-            Some(ref command) => {
-                println!("{}", Colour::Green.paint(format!("Running `{}`", command)));
-                match command.split(' ').collect::<Vec<&str>>().as_slice() {
-                    [cmd, args..] => {
-                        let mut p = Command::new(cmd)
-                            .stdin(Stdio::piped())
-                            .stderr(Stdio::piped())
-                            .stdout(Stdio::piped())
-                            .args(args)
-                            .spawn()?;
-
-                        p.wait()?;
-                    }
-                    _ => (),
-                }
-            }
+        if let Some(ref command) = self.shell {
+            println!("{}", Colour::Green.paint(format!("Running `{}`", command)));
+            if let [cmd, args @ ..] = command.split(' ').collect::<Vec<&str>>().as_slice() {
+                let mut p = Command::new(cmd)
+                    .stdin(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .args(args)
+                    .spawn()?;
 
-            _ => (),
+                p.wait()?;
+            }
         };
         Ok(())
     }
 }
 
 impl<'a> Module<'a> {
-    pub fn new(name: &'a str, target_config: Config) -> errors::Result<Self> {
-        let host_config = HostConfig::default();
+    pub fn new(
+        name: &'a str,
+        target_config: Config,
+        options: &'a Options,
+        backup: &'a Backup,
+        manifest: &'a Manifest,
+    ) -> errors::Result<Self> {
         let mut module = Module {
-            host_config,
+            host_config: HostConfig::detect(),
             name,
             target_config,
             module_config: None,
+            module_vars: None,
+            options,
+            backup,
+            manifest,
         };
         module.maybe_load_module_config()?;
+        module.target_config = target_vars(
+            module.target_config.take(),
+            &module.module_config,
+            &options.set,
+        );
+
+        let overrides = module
+            .target_config
+            .as_ref()
+            .and_then(|c| c.get("module_overrides"))
+            .and_then(|o| o.get(name));
+        module.module_vars = template_vars(&module.module_config, overrides);
+
         Ok(module)
     }
 
+    /// The variables this module's templates see under `target.*` (see `target_vars`).
+    pub fn target_config(&self) -> &Config {
+        &self.target_config
+    }
+
     fn maybe_load_module_config(&mut self) -> errors::Result<()> {
         let conf_path = format!("modules/{}/config.toml", self.name);
         let path = Path::new(&conf_path);
@@ -73,9 +129,14 @@ impl<'a> Module<'a> {
             return Ok(());
         }
 
-        let template =
-            Template::new_from_file(&conf_path, &self.host_config, &self.target_config, &None)?
-                .render();
+        let template = Template::new_from_file(
+            &conf_path,
+            self.host_config,
+            &self.target_config,
+            &None,
+            &self.options.set,
+        )?
+        .render()?;
 
         let module_config = template.parse::<toml::Value>()?;
 
@@ -83,22 +144,55 @@ impl<'a> Module<'a> {
         Ok(())
     }
 
-    pub fn process(&self) -> errors::Result<()> {
+    /// Modules that must be processed before this one, from `depends_on` in the module config.
+    pub fn depends_on(&self) -> errors::Result<Vec<String>> {
+        let depends_on = match self
+            .module_config
+            .as_ref()
+            .and_then(|c| c.get("depends_on"))
+        {
+            Some(depends_on) => depends_on,
+            None => return Ok(vec![]),
+        };
+
+        depends_on
+            .as_array()
+            .and_then(|v| v.iter().map(|d| d.as_str().map(String::from)).collect())
+            .ok_or_else(|| {
+                Error::new(ErrorKind::Config(format!(
+                    "modules/{}/config.toml: `depends_on` should be a list of module names, not `{}`",
+                    self.name, depends_on
+                )))
+            })
+    }
+
+    /// Applies what the module rendered for this run (see `render`).
+    pub fn process(&self, rendered: &mut Rendered) -> errors::Result<Report> {
         self.process_repos()?;
-        self.process_templates()?;
+        let mut report = self.process_outputs(&mut rendered.outputs)?;
+        report.merge(self.prune(rendered)?);
         self.process_after_commits()?;
 
-        Ok(())
+        Ok(report)
     }
 
     fn process_repos(&self) -> errors::Result<()> {
         match self.module_config {
             Some(ref toml) => {
-                match &toml.get("repos") {
+                match toml.get("repos") {
                     Some(toml::Value::Array(v)) => {
                         for r in v.iter() {
-                            let repo = r.clone().try_into::<RepoConfig>().expect("hmm");
-                            repo.go_do().unwrap();
+                            let repo = r.clone().try_into::<RepoConfig>().map_err(|e| {
+                                Error::new(ErrorKind::Config(format!(
+                                    "modules/{}/config.toml: invalid entry in `repos`: {}",
+                                    self.name, e
+                                )))
+                            })?;
+                            if self.options.dry_run() {
+                                repo.dry_run();
+                            } else {
+                                repo.go_do()?;
+                            }
                         }
                     }
                     _ => {
@@ -118,82 +212,498 @@ impl<'a> Module<'a> {
     }
 
     fn process_after_commits(&self) -> errors::Result<()> {
-        match self.module_config {
-            Some(ref toml) => match &toml.get("after_commit") {
-                Some(toml::Value::Array(v)) => {
-                    for r in v.iter() {
-                        let hook = r.clone().try_into::<AfterCommitHook>().expect("hmm");
+        if let Some(ref toml) = self.module_config {
+            if let Some(toml::Value::Array(v)) = toml.get("after_commit") {
+                for r in v.iter() {
+                    let hook = r.clone().try_into::<AfterCommitHook>().map_err(|e| {
+                        Error::new(ErrorKind::Config(format!(
+                            "modules/{}/config.toml: invalid entry in `after_commit`: {}",
+                            self.name, e
+                        )))
+                    })?;
+                    if self.options.dry_run() {
+                        hook.dry_run();
+                    } else {
                         hook.process()?;
                     }
                 }
-                _ => {}
-            },
-
-            _ => {}
+            }
         };
 
         Ok(())
     }
 
-    fn process_templates(&self) -> errors::Result<()> {
+    fn process_outputs(&self, outputs: &mut [Output]) -> errors::Result<Report> {
+        let mut report = Report::default();
+
+        for output in outputs {
+            // an earlier output may have written to the same file since it was rendered
+            output.refresh()?;
+            report.record(self.process_output(output)?);
+        }
+
+        Ok(report)
+    }
+
+    /// Renders everything this module writes: its own outputs (its templates, then its static
+    /// files), and the fragments it contributes to shared files (see `fragment::assemble`).
+    pub fn render(&self) -> errors::Result<Rendered> {
+        let mut rendered = Rendered::default();
+
         for path in self.template_paths()? {
-            self.process_template(path.unwrap())?;
+            let template = self.load_template(&path)?;
+            match template.fragment_of() {
+                Some(fragment_of) => {
+                    rendered
+                        .fragments
+                        .push(self.fragment(&path, &template, fragment_of)?)
+                }
+                None => rendered
+                    .outputs
+                    .push(self.template_output(&path, &template)?),
+            }
         }
 
-        Ok(())
+        for path in self.file_paths()? {
+            rendered.outputs.push(self.file_output(&path)?);
+        }
+
+        Ok(rendered)
+    }
+
+    fn fragment(
+        &self,
+        path: &Path,
+        template: &Template,
+        fragment_of: &str,
+    ) -> errors::Result<Fragment> {
+        if template.strategy() != "replace" {
+            return Err(Error::new(ErrorKind::Config(format!(
+                "{}: a fragment can't set `strategy`",
+                path.display()
+            ))));
+        }
+
+        Ok(Fragment {
+            module: self.name.to_owned(),
+            source: path.to_path_buf(),
+            target_path: util::expand_home(fragment_of),
+            order: template.order(),
+            contents: template.render()?,
+            attributes: template.attributes()?,
+            warning: template.warning(),
+        })
     }
 
-    fn template_paths(&self) -> std::io::Result<fs::ReadDir> {
-        fs::read_dir(Path::new(&format!("modules/{}/templates/", self.name)))
+    /// Files this module applied in an earlier run that none of its outputs produce any more.
+    /// Files assembled from fragments are only orphaned once no module contributes to them, so
+    /// they're left to `fragment::orphans`.
+    pub fn orphans(&self, rendered: &Rendered) -> errors::Result<Vec<manifest::Entry>> {
+        let mut current: Vec<(PathBuf, Option<&Markers>)> = rendered
+            .outputs
+            .iter()
+            .map(|o| (util::absolute_path(o.path()), o.block.as_ref()))
+            .collect();
+        // a template that became a fragment still produces its file
+        current.extend(
+            rendered
+                .fragments
+                .iter()
+                .map(|f| (util::absolute_path(Path::new(&f.target_path)), None)),
+        );
+
+        Ok(self
+            .manifest
+            .module_entries(self.name)?
+            .into_iter()
+            .filter(|e| {
+                e.contributors.is_empty()
+                    && !current.iter().any(|(p, block)| {
+                        Path::new(&e.target_path) == p && e.block.as_ref() == *block
+                    })
+            })
+            .collect())
     }
 
-    fn process_template(&self, path: std::fs::DirEntry) -> errors::Result<()> {
-        let template = Template::new_from_file(
+    /// Offers to remove this module's orphaned files (see `orphans`).
+    pub fn prune(&self, rendered: &Rendered) -> errors::Result<Report> {
+        prune::remove(
+            &self.orphans(rendered)?,
+            self.options,
+            self.backup,
+            self.manifest,
+        )
+    }
+
+    fn templates_dir(&self) -> PathBuf {
+        Path::new("modules").join(self.name).join("templates")
+    }
+
+    /// Every file under `modules/<name>/templates/`, including subdirectories.
+    fn template_paths(&self) -> std::io::Result<Vec<PathBuf>> {
+        if !self.templates_dir().is_dir() {
+            return Ok(vec![]);
+        }
+
+        util::walk(&self.templates_dir())
+    }
+
+    fn files_dir(&self) -> PathBuf {
+        Path::new("modules").join(self.name).join("files")
+    }
+
+    /// Every file under `modules/<name>/files/`, including subdirectories.
+    fn file_paths(&self) -> std::io::Result<Vec<PathBuf>> {
+        if !self.files_dir().is_dir() {
+            return Ok(vec![]);
+        }
+
+        util::walk(&self.files_dir())
+    }
+
+    fn module_config_str(&self, key: &str) -> Option<&str> {
+        self.module_config.as_ref()?.get(key)?.as_str()
+    }
+
+    /// `target_root` from the module config: templates without a `target_path` are written to
+    /// their path under `templates/`, relative to it. A module-level `comment_format` likewise
+    /// applies to templates that don't set their own.
+    fn target_root(&self) -> Option<&str> {
+        self.module_config_str("target_root")
+    }
+
+    /// `files_root` from the module config (defaulting to `target_root`): static files are
+    /// copied to their path under `files/`, relative to it.
+    fn files_root(&self) -> Option<&str> {
+        self.module_config_str("files_root")
+            .or_else(|| self.target_root())
+    }
+
+    fn load_template(&self, path: &Path) -> errors::Result<Template<'_>> {
+        let mut template = Template::new_from_file(
             // FIXME: should new_from_file take a path instead?
-            path.path().to_str().expect(""),
-            &self.host_config,
+            path.to_str().expect(""),
+            self.host_config,
             &self.target_config,
-            &None,
+            &self.module_vars,
+            &self.options.set,
         )?;
 
+        if let Some(root) = self.target_root() {
+            let relative = path
+                .strip_prefix(self.templates_dir())
+                .expect("template is under templates/");
+            let target_path = Path::new(root).join(relative);
+            template.default_frontmatter(
+                "target_path",
+                toml::Value::String(target_path.to_string_lossy().into_owned()),
+            );
+        }
+
+        if let Some(format) = self
+            .module_config
+            .as_ref()
+            .and_then(|c| c.get("comment_format"))
+        {
+            template.default_frontmatter("comment_format", format.clone());
+        }
+
+        if template.target_path().is_none() && template.fragment_of().is_none() {
+            return Err(Error::new(ErrorKind::Config(format!(
+                "{} has no `target_path` in its frontmatter, and module `{}` has no `target_root`",
+                path.display(),
+                self.name
+            ))));
+        }
+
+        Ok(template)
+    }
+
+    fn template_output(&self, path: &Path, template: &Template) -> errors::Result<Output> {
         let target_path = template.target_path().expect("target path exists");
-        // TODO:
-        // commands (apply changes similar to git -p)?
-        //
-        // patch apply:
-        //
-        // stdin | git diff --no-index target/file/to/change - | patch -p1 target/file/to/change
-        //
-        // could intercept the second pipe and interactively stage individual hunks
-        let diff = template.diff();
-
-        let file_exists = Path::new(target_path).is_file();
-
-        if diff.is_empty() && file_exists {
+
+        let current = || -> errors::Result<String> {
+            if Path::new(target_path).is_file() {
+                util::read_file_to_string(Path::new(target_path))
+            } else {
+                Ok(String::new())
+            }
+        };
+
+        let (contents, warning, block, merge) = match template.strategy() {
+            "replace" => (
+                template.render_with_warning()?,
+                Some(template.warning()),
+                None,
+                None,
+            ),
+            "block" => {
+                let markers = Markers::new(template.comment_format(), self.name);
+                let contents = markers.replace(&current()?, &template.render()?)?;
+                (contents, None, Some(markers), None)
+            }
+            "merge" => {
+                let format = match template.format() {
+                    Some(name) => merge::Format::from_name(name),
+                    None => merge::Format::for_path(Path::new(target_path)),
+                }
+                .ok_or_else(|| {
+                    Error::new(ErrorKind::Config(format!(
+                        "{}: set `format` to json, toml, yaml or ini to merge into {}",
+                        path.display(),
+                        target_path
+                    )))
+                })?;
+
+                let values = format.parse(&template.render()?)?;
+                let mut merged = format.parse(&current()?).map_err(|e| {
+                    Error::new(ErrorKind::Config(format!(
+                        "couldn't parse {} to merge into it: {}",
+                        target_path, e
+                    )))
+                })?;
+                merge::merge(&mut merged, &values);
+
+                let merge = merge::Merge {
+                    format,
+                    template: values,
+                };
+                (format.serialize(&merged)?, None, None, Some(merge))
+            }
+            strategy => {
+                return Err(Error::new(ErrorKind::Config(format!(
+                    "{}: unknown strategy `{}` (expected \"replace\", \"block\" or \"merge\")",
+                    path.display(),
+                    strategy
+                ))))
+            }
+        };
+
+        Ok(Output {
+            source: path.to_path_buf(),
+            target_path: target_path.to_owned(),
+            contents: contents.into_bytes(),
+            attributes: template.attributes()?,
+            warning,
+            link: false,
+            block,
+            merge,
+            contributors: vec![],
+        })
+    }
+
+    /// How a static file is installed: `copy` (the default) or `symlink`, from
+    /// `[files."<path under files/>"]` in the module config, or the module-wide `install`.
+    fn file_install(&self, relative: &Path) -> errors::Result<&str> {
+        let config = self.module_config.as_ref();
+        let install = config
+            .and_then(|c| c.get("files"))
+            .and_then(|f| f.get(relative.to_string_lossy().as_ref()))
+            .and_then(|f| f.get("install"))
+            .or_else(|| config.and_then(|c| c.get("install")));
+
+        match install.map(|i| i.as_str()) {
+            None => Ok("copy"),
+            Some(Some(install @ ("copy" | "symlink"))) => Ok(install),
+            Some(_) => Err(Error::new(ErrorKind::Config(format!(
+                "invalid `install` for {} in module `{}` (expected \"copy\" or \"symlink\")",
+                relative.display(),
+                self.name
+            )))),
+        }
+    }
+
+    /// A static file, copied byte for byte (executable files stay executable), or linked to
+    /// from the target with `install = "symlink"`.
+    fn file_output(&self, path: &Path) -> errors::Result<Output> {
+        let root = self.files_root().ok_or_else(|| {
+            Error::new(ErrorKind::Config(format!(
+                "module `{}` has a files/ directory, but no `files_root` (or `target_root`)",
+                self.name
+            )))
+        })?;
+        let relative = path
+            .strip_prefix(self.files_dir())
+            .expect("file is under files/");
+        let target_path = Path::new(root)
+            .join(relative)
+            .to_string_lossy()
+            .into_owned();
+
+        if self.file_install(relative)? == "symlink" {
+            let dest = util::absolute_path(path);
+            return Ok(Output {
+                source: path.to_path_buf(),
+                target_path,
+                contents: dest.as_os_str().as_bytes().to_vec(),
+                attributes: Default::default(),
+                warning: None,
+                link: true,
+                block: None,
+                merge: None,
+                contributors: vec![],
+            });
+        }
+
+        let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+
+        Ok(Output {
+            source: path.to_path_buf(),
+            target_path,
+            contents: fs::read(path)?,
+            attributes: util::Attributes {
+                mode: if mode & 0o111 != 0 { Some(mode) } else { None },
+                ..Default::default()
+            },
+            warning: None,
+            link: false,
+            block: None,
+            merge: None,
+            contributors: vec![],
+        })
+    }
+
+    /// Compares every output with its target file, without writing anything.
+    pub fn status(&self, rendered: &Rendered) -> errors::Result<Vec<status::Entry>> {
+        let mut entries = Vec::new();
+
+        for output in &rendered.outputs {
+            entries.push(status::Entry {
+                module: self.name.to_owned(),
+                target_path: output.target_path.clone(),
+                status: status::check(
+                    output,
+                    self.manifest
+                        .get(output.path(), output.block.as_ref())?
+                        .as_ref(),
+                )?,
+            });
+        }
+
+        for orphan in self.orphans(rendered)? {
+            if Path::new(&orphan.target_path).is_file() {
+                entries.push(status::Entry {
+                    module: self.name.to_owned(),
+                    target_path: orphan.target_path,
+                    status: status::FileStatus::Orphaned,
+                });
+            }
+        }
+
+        Ok(entries)
+    }
+
+    pub fn process_output(&self, output: &Output) -> errors::Result<Outcome> {
+        if output.link {
+            return self.process_link(output);
+        }
+
+        let target_path = output.target_path.as_str();
+        let attributes = &output.attributes;
+        let hunks = output.hunks(self.options.context)?;
+        let file_exists = output.path().is_file();
+
+        if output.is_up_to_date()? {
+            if attributes.differ(output.path())? {
+                return self.fix_attributes(target_path, attributes);
+            }
+
             println!(
                 "{} {}",
                 Colour::Green.bold().paint(target_path),
                 Colour::Cyan.bold().paint("is up to date.")
             );
 
-            return Ok(());
+            if !self.options.dry_run() {
+                self.adopt(output)?;
+            }
+
+            return Ok(Outcome::Unchanged);
+        }
+
+        let edited = if file_exists {
+            self.local_edits(output)?
+        } else {
+            None
+        };
+
+        if let Some(ref entry) = edited {
+            self.print_local_edits(entry, output)?;
+        }
+
+        // files edited by hand are only overwritten wholesale with --force
+        let blocked = edited.is_some() && !self.options.force;
+
+        let diff = match hunks {
+            Some(ref hunks) if hunks.is_empty() => format!(
+                "{} only differs in line endings or the final newline\n",
+                target_path
+            ),
+            Some(ref hunks) => diff::unified(target_path, hunks, self.options.color),
+            None => format!("Binary file {} differs\n", target_path),
+        };
+
+        match self.options.mode {
+            Mode::DryRun => {
+                println!("{}", diff);
+                println!(
+                    "{} {}",
+                    Colour::Yellow.paint("Would write"),
+                    Colour::Green.bold().paint(target_path)
+                );
+                return Ok(Outcome::Pending);
+            }
+            Mode::Yes => {
+                println!("{}", diff);
+                if blocked {
+                    println!(
+                        "{} {}",
+                        Colour::Green.bold().paint(target_path),
+                        Colour::Red.paint("has local edits; not overwriting without --force.")
+                    );
+                    return Ok(Outcome::Skipped);
+                }
+                return self.write_file(output, &output.contents);
+            }
+            Mode::Interactive => (),
         }
 
         let mut less = std::process::Command::new("less");
         let mut child = less.stdin(std::process::Stdio::piped()).spawn().unwrap();
 
-        child.stdin.as_mut().map(|x| {
-            x.write_all(template.diff().as_bytes()).ok();
-        });
+        if let Some(x) = child.stdin.as_mut() {
+            x.write_all(diff.as_bytes()).ok();
+        }
 
         child.wait().unwrap();
 
-        if file_exists {
+        // hunk mode needs text on both sides
+        let patchable = file_exists && hunks.as_ref().is_some_and(|h| !h.is_empty());
+
+        if blocked && patchable {
+            println!(
+                "{} {} {}",
+                Colour::Green.bold().paint(target_path),
+                Colour::Red.paint("has local edits and won't be overwritten without --force."),
+                Colour::Yellow.bold().paint("Apply hunk by hunk? [p/N]")
+            );
+        } else if blocked {
+            println!(
+                "{} {}",
+                Colour::Green.bold().paint(target_path),
+                Colour::Red.paint("has local edits; not overwriting without --force.")
+            );
+            return Ok(Outcome::Skipped);
+        } else if file_exists {
             println!(
                 "{} {} {}",
                 Colour::Yellow.paint("Apply changes?"),
                 Colour::Green.bold().paint(target_path),
-                Colour::Yellow.bold().paint("will be overwritten. [Y/n]")
+                Colour::Yellow.bold().paint(if patchable {
+                    "will be overwritten. [Y/n/p]"
+                } else {
+                    "will be overwritten. [Y/n]"
+                })
             );
         } else {
             println!(
@@ -207,40 +717,341 @@ impl<'a> Module<'a> {
         let mut input = String::new();
 
         match io::stdin().read_line(&mut input) {
-            Ok(_n) => match input.as_str().trim() {
-                "y" | "Y" => {
+            Ok(_n) => match (input.as_str().trim(), hunks) {
+                ("y" | "Y", _) if !blocked => self.write_file(output, &output.contents),
+                ("p" | "P", Some(hunks)) if patchable => self.patch_file(output, hunks),
+                _ => Ok(Outcome::Skipped),
+            },
+
+            Err(n) => {
+                println!("{}", Colour::Red.paint(format!("error: {}", n)));
+                Err(n.into())
+            }
+        }
+    }
+
+    /// Creates or verifies a symlink from the target path to the source in the config repo.
+    /// Anything else in its place (a regular file, or a broken or foreign link) is only
+    /// replaced after confirmation, and a file whose contents differ from the source needs
+    /// `--force` with `--yes`.
+    fn process_link(&self, output: &Output) -> errors::Result<Outcome> {
+        let target_path = output.target_path.as_str();
+        let dest = output.link_dest();
+
+        let (problem, lossless) = match output.link_state()? {
+            LinkState::Linked => {
+                println!(
+                    "{} {}",
+                    Colour::Green.bold().paint(target_path),
+                    Colour::Cyan.bold().paint("is linked.")
+                );
+
+                if !self.options.dry_run() {
+                    self.adopt(output)?;
+                }
+
+                return Ok(Outcome::Unchanged);
+            }
+            LinkState::Missing => (None, true),
+            LinkState::Broken(old) => (
+                Some(format!("is a broken link to {}.", old.display())),
+                true,
+            ),
+            LinkState::Foreign(old) => {
+                (Some(format!("links to {} instead.", old.display())), false)
+            }
+            LinkState::File => {
+                let current = fs::read(output.path())?;
+                let source = fs::read(&output.source)?;
+
+                if let (Ok(current), Ok(source)) = (
+                    String::from_utf8(current.clone()),
+                    String::from_utf8(source.clone()),
+                ) {
                     println!(
                         "{}",
-                        Colour::Yellow.paint(format!("saving `{}`...", &target_path))
+                        diff::unified(
+                            target_path,
+                            &diff::diff(&current, &source, self.options.context),
+                            self.options.color
+                        )
                     );
-                    let path = Path::new(&target_path);
-                    mkdir_p(&path);
-                    let mut file = match File::create(&path) {
-                        Err(e) => panic!("couldn't create {}: {}", path.display(), e.description()),
-                        Ok(file) => file,
-                    };
-
-                    match file.write_all(template.render_with_warning().as_bytes()) {
-                        Err(e) => panic!("couldn't write {}: {}", path.display(), e.description()),
-                        Ok(_) => {
-                            println!("{}", Colour::Green.paint("Done!"));
-                            Ok(())
-                        }
-                    }
                 }
-                _ => Ok(()),
-            },
 
-            Err(n) => {
-                println!("{}", Colour::Red.paint(format!("error: {}", n)));
-                Ok(()) // FIXME: Err?
+                (Some("is a regular file.".to_owned()), current == source)
             }
+        };
+
+        if let Some(ref problem) = problem {
+            println!(
+                "{} {}",
+                Colour::Green.bold().paint(target_path),
+                Colour::Yellow.paint(problem.as_str())
+            );
+        }
+
+        match self.options.mode {
+            Mode::DryRun => {
+                println!(
+                    "{} {} -> {}",
+                    Colour::Yellow.paint("Would link"),
+                    Colour::Green.bold().paint(target_path),
+                    dest.display()
+                );
+                return Ok(Outcome::Pending);
+            }
+            Mode::Yes if !lossless && !self.options.force => {
+                println!(
+                    "{} {}",
+                    Colour::Green.bold().paint(target_path),
+                    Colour::Red.paint("would be lost; not replacing it without --force.")
+                );
+                return Ok(Outcome::Skipped);
+            }
+            Mode::Yes => (),
+            Mode::Interactive => {
+                println!(
+                    "{} {} -> {} {}",
+                    Colour::Yellow.paint("Link"),
+                    Colour::Green.bold().paint(target_path),
+                    dest.display(),
+                    Colour::Yellow.bold().paint(if problem.is_some() {
+                        "replacing it? [y/N]"
+                    } else {
+                        "[Y/n]"
+                    })
+                );
+
+                let mut input = String::new();
+                io::stdin().read_line(&mut input)?;
+                let accepted = match input.trim() {
+                    "y" | "Y" => true,
+                    "" => problem.is_none(),
+                    _ => false,
+                };
+                if !accepted {
+                    return Ok(Outcome::Skipped);
+                }
+            }
+        }
+
+        let path = output.path();
+        self.backup.save(path)?;
+        util::symlink_atomic(&dest, path)?;
+        self.manifest.record(self.name, output, &output.contents)?;
+
+        println!("{}", Colour::Green.paint("Done!"));
+        Ok(Outcome::Changed)
+    }
+
+    /// Applies the new contents hunk by hunk, keeping the current file's content for every hunk
+    /// that isn't accepted.
+    fn patch_file(&self, output: &Output, hunks: Vec<Hunk>) -> errors::Result<Outcome> {
+        let accepted = patch::select(hunks, self.options.color)?;
+
+        if accepted.is_empty() {
+            return Ok(Outcome::Skipped);
         }
+
+        let current = util::read_file_to_string(output.path())?;
+        self.write_file(output, diff::apply(&current, &accepted).as_bytes())
+    }
+
+    /// The manifest entry for an output if what's on disk (for a block, just the block) no
+    /// longer matches what zotfile last wrote there.
+    fn local_edits(&self, output: &Output) -> errors::Result<Option<manifest::Entry>> {
+        let entry = self.manifest.get(output.path(), output.block.as_ref())?;
+
+        Ok(match entry {
+            Some(entry) if Some(&entry.hash) != output.managed_hash_on_disk()?.as_ref() => {
+                Some(entry)
+            }
+            _ => None,
+        })
+    }
+
+    /// Shows a three-way view of a file edited by hand: what zotfile last applied compared with
+    /// the file on disk, and with the new contents.
+    fn print_local_edits(&self, entry: &manifest::Entry, output: &Output) -> errors::Result<()> {
+        println!(
+            "{} {}",
+            Colour::Green.bold().paint(&output.target_path),
+            Colour::Yellow.bold().paint(format!(
+                "was edited since zotfile last applied it ({}).",
+                entry.applied_at
+            ))
+        );
+
+        let last_applied = match self.manifest.applied_contents(entry) {
+            Some(contents) => contents,
+            None => return Ok(()),
+        };
+        // binary files get no diff; for a block or a merge, only the managed part is compared
+        let current = output.current()?.unwrap_or_default();
+        let (current, new) = match (
+            output
+                .managed(&current)
+                .map(|m| String::from_utf8(m.into_owned())),
+            output
+                .managed(&output.contents)
+                .map(|m| String::from_utf8(m.into_owned())),
+        ) {
+            (Some(Ok(current)), Some(Ok(new))) => (current, new),
+            (None, Some(Ok(new))) => (String::new(), new),
+            _ => return Ok(()),
+        };
+        let (context, color) = (self.options.context, self.options.color);
+
+        println!(
+            "{}",
+            diff::unified_between(
+                "last applied",
+                "on disk",
+                &diff::diff(&last_applied, &current, context),
+                color
+            )
+        );
+        println!(
+            "{}",
+            diff::unified_between(
+                "last applied",
+                "new render",
+                &diff::diff(&last_applied, &new, context),
+                color
+            )
+        );
+
+        Ok(())
     }
+
+    /// Records an up-to-date output in the manifest if zotfile has no record of writing it, e.g.
+    /// because it was applied before the manifest existed.
+    fn adopt(&self, output: &Output) -> errors::Result<()> {
+        let path = output.path();
+        let contents = if output.link {
+            fs::read_link(path)?.into_os_string().into_vec()
+        } else {
+            fs::read(path)?
+        };
+        let managed = output.managed(&contents).unwrap_or_default();
+
+        match self.manifest.get(path, output.block.as_ref())? {
+            Some(ref entry)
+                if entry.hash == manifest::hash(&managed)
+                    && entry.contributors == output.contributors =>
+            {
+                Ok(())
+            }
+            _ => self.manifest.record(self.name, output, &managed),
+        }
+    }
+
+    /// Brings the mode and ownership of an otherwise up-to-date file in line with the template.
+    fn fix_attributes(
+        &self,
+        target_path: &str,
+        attributes: &util::Attributes,
+    ) -> errors::Result<Outcome> {
+        println!(
+            "{} {}",
+            Colour::Green.bold().paint(target_path),
+            Colour::Yellow.paint("has different permissions or ownership.")
+        );
+
+        match self.options.mode {
+            Mode::DryRun => return Ok(Outcome::Pending),
+            Mode::Interactive => {
+                println!("{}", Colour::Yellow.bold().paint("Fix? [Y/n]"));
+
+                let mut input = String::new();
+                io::stdin().read_line(&mut input)?;
+                if !matches!(input.trim(), "y" | "Y") {
+                    return Ok(Outcome::Skipped);
+                }
+            }
+            Mode::Yes => (),
+        }
+
+        attributes
+            .apply(Path::new(target_path))
+            .map_err(|e| Error::new(ErrorKind::Write(target_path.into(), e.kind())))?;
+
+        println!("{}", Colour::Green.paint("Done!"));
+        Ok(Outcome::Changed)
+    }
+
+    fn write_file(&self, output: &Output, contents: &[u8]) -> errors::Result<Outcome> {
+        println!(
+            "{}",
+            Colour::Yellow.paint(format!("saving `{}`...", output.target_path))
+        );
+        let path = output.path();
+        self.backup.save(path)?;
+        util::write_atomic(path, contents, &output.attributes)?;
+        self.manifest.record(
+            self.name,
+            output,
+            &output.managed(contents).unwrap_or_default(),
+        )?;
+
+        println!("{}", Colour::Green.paint("Done!"));
+        Ok(Outcome::Changed)
+    }
+}
+
+/// What templates see under `target.*`: the target config (with its overrides for the module),
+/// the `[vars]` table of the module config merged over it, and `--set` over that again.
+fn target_vars(target_config: Config, module_config: &Config, set: &Config) -> Config {
+    let vars = module_config.as_ref().and_then(|c| c.get("vars"));
+    let mut config = target_config;
+
+    for layer in vars.into_iter().chain(set) {
+        match config {
+            Some(ref mut config) => config::merge(config, layer),
+            None => config = Some(layer.clone()),
+        }
+    }
+
+    config
+}
+
+/// What templates see under `module.*`: the rendered `config.toml`, with the values of its
+/// `[defaults]` table at the top level, and the target's `[module_overrides.<module>]` merged
+/// over those, so a target can change a module's defaults without repeating the rest. These are
+/// kept apart from `[overrides.<module>]`, which only ever changes `target.*`.
+fn template_vars(module_config: &Config, overrides: Option<&toml::Value>) -> Config {
+    let mut vars = module_config
+        .clone()
+        .unwrap_or_else(|| toml::Value::Table(Default::default()));
+    let defaults = vars.get("defaults").cloned();
+
+    for layer in defaults.iter().chain(overrides) {
+        config::merge(&mut vars, layer);
+    }
+
+    Some(vars)
+}
+
+pub fn exists(name: &str) -> bool {
+    Path::new("modules").join(name).is_dir()
 }
 
-fn mkdir_p(path: &Path) -> () {
-    dbg!(path.parent().map(|x| { std::fs::create_dir_all(x) }));
+/// Lists every module directory under `modules/`, sorted by name so that `--all` runs in a
+/// deterministic order.
+pub fn discover() -> errors::Result<Vec<String>> {
+    let mut names = Vec::new();
+
+    for entry in fs::read_dir("modules")? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            if let Some(name) = entry.file_name().to_str() {
+                names.push(name.to_owned());
+            }
+        }
+    }
+
+    names.sort();
+    Ok(names)
 }
 
 #[cfg(test)]
@@ -250,11 +1061,131 @@ mod test {
 
     #[test]
     fn test_module_config() {
-        let target = "configs/manjaro.toml";
         let target_config = config::load_target_config("manjaro").unwrap();
 
         dbg!(&target_config);
 
-        let module = dbg!(Module::new("test", target_config));
+        let options = Options {
+            mode: Mode::Interactive,
+            color: false,
+            context: 3,
+            force: false,
+            set: None,
+        };
+
+        let backup = Backup::new();
+        let manifest = Manifest::for_target("manjaro");
+
+        let module = dbg!(Module::new(
+            "test",
+            target_config,
+            &options,
+            &backup,
+            &manifest
+        ));
+        assert!(module.is_ok());
+    }
+
+    #[test]
+    fn test_overrides_stay_apart() {
+        let target_config = Some(
+            r#"
+            [overrides.test]
+            prefix = "target"
+
+            [module_overrides.test]
+            prefix = "module"
+            "#
+            .parse::<toml::Value>()
+            .unwrap(),
+        );
+        let options = Options {
+            mode: Mode::Interactive,
+            color: false,
+            context: 3,
+            force: false,
+            set: None,
+        };
+        let backup = Backup::new();
+        let manifest = Manifest::for_target("test");
+
+        let target_config = config::target_config_for_module(&target_config, "test");
+        let module = Module::new("test", target_config, &options, &backup, &manifest).unwrap();
+        assert_eq!(
+            module.target_config().as_ref().unwrap()["prefix"].as_str(),
+            Some("target")
+        );
+        assert_eq!(
+            module.module_vars.as_ref().unwrap()["prefix"].as_str(),
+            Some("module")
+        );
+    }
+
+    #[test]
+    fn test_depends_on() {
+        let options = Options {
+            mode: Mode::Interactive,
+            color: false,
+            context: 3,
+            force: false,
+            set: None,
+        };
+        let backup = Backup::new();
+        let manifest = Manifest::for_target("test");
+        let mut module = Module::new("test", None, &options, &backup, &manifest).unwrap();
+        let mut depends_on = |toml: &str| {
+            module.module_config = Some(toml.parse::<toml::Value>().unwrap());
+            module.depends_on()
+        };
+
+        assert_eq!(
+            depends_on("depends_on = [\"fonts\", \"zsh\"]").unwrap(),
+            vec!["fonts", "zsh"]
+        );
+        assert!(depends_on("").unwrap().is_empty());
+        assert!(depends_on("depends_on = \"fonts\"").is_err());
+        assert!(depends_on("depends_on = [\"fonts\", 1]").is_err());
+    }
+
+    #[test]
+    fn test_template_vars() {
+        let module_config = Some(
+            r#"
+            target_root = "~/.config/tmux"
+
+            [defaults]
+            prefix = "C-b"
+            font_size = 12
+            "#
+            .parse::<toml::Value>()
+            .unwrap(),
+        );
+        let overrides = "prefix = \"C-a\"".parse::<toml::Value>().unwrap();
+
+        let vars = template_vars(&module_config, Some(&overrides)).unwrap();
+        assert_eq!(vars["prefix"].as_str(), Some("C-a"));
+        assert_eq!(vars["font_size"].as_integer(), Some(12));
+        assert_eq!(vars["target_root"].as_str(), Some("~/.config/tmux"));
+
+        assert_eq!(
+            template_vars(&None, None),
+            Some(toml::Value::Table(Default::default()))
+        );
+    }
+
+    #[test]
+    fn test_target_vars() {
+        let parse = |s: &str| Some(s.parse::<toml::Value>().unwrap());
+        let target_config = parse("font_size = 14\nshell = \"zsh\"\n[colors]\nbg = \"#000\"\n");
+        let module_config = parse("[vars]\nfont_size = 12\n[vars.colors]\nfg = \"#fff\"\n");
+        let set = parse("shell = \"fish\"");
+
+        let vars = target_vars(target_config, &module_config, &set).unwrap();
+        assert_eq!(vars["font_size"].as_integer(), Some(12));
+        assert_eq!(vars["shell"].as_str(), Some("fish"));
+        assert_eq!(vars["colors"]["bg"].as_str(), Some("#000"));
+        assert_eq!(vars["colors"]["fg"].as_str(), Some("#fff"));
+
+        assert_eq!(target_vars(None, &None, &None), None);
     }
 }
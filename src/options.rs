@@ -0,0 +1,33 @@
+use crate::config::Config;
+
+/// How changes are applied once a diff has been rendered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Page each diff through `less` and ask before writing.
+    Interactive,
+    /// Apply every change without prompting (`--yes`).
+    Yes,
+    /// Render and diff everything, but write nothing, clone nothing and run no hooks
+    /// (`--dry-run`).
+    DryRun,
+}
+
+/// Run-wide settings from the command line, shared by every module in the run.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub mode: Mode,
+    /// Whether diffs are coloured (`--color`, resolved against the terminal for `auto`).
+    pub color: bool,
+    /// Lines of context around each diff hunk (`--context`).
+    pub context: usize,
+    /// Overwrite files that were edited since zotfile last applied them (`--force`).
+    pub force: bool,
+    /// Variables from `--set key=value`, which take precedence over every other layer.
+    pub set: Config,
+}
+
+impl Options {
+    pub fn dry_run(&self) -> bool {
+        self.mode == Mode::DryRun
+    }
+}
@@ -0,0 +1,231 @@
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::block::Markers;
+use crate::diff::{self, Hunk};
+use crate::errors;
+use crate::manifest;
+use crate::merge::{self, Merge};
+use crate::util;
+
+/// A file a module wants on disk: a rendered template or a static file, with everything needed
+/// to diff it against the target and write it.
+#[derive(Debug)]
+pub struct Output {
+    /// The template or static file in the config repo it comes from.
+    pub source: PathBuf,
+    pub target_path: String,
+    pub contents: Vec<u8>,
+    pub attributes: util::Attributes,
+    /// The zotfile header written at the top of rendered templates, used by `status` to
+    /// recognise files zotfile wrote before the manifest existed.
+    pub warning: Option<String>,
+    /// Whether the target is a symlink to `source` rather than a copy (`install = "symlink"`).
+    /// For links, `contents` is the link's destination.
+    pub link: bool,
+    /// For a managed block (`strategy = "block"`), its markers. `contents` is then the whole
+    /// file with the block updated, but zotfile only tracks the block itself.
+    pub block: Option<Markers>,
+    /// For a structured merge (`strategy = "merge"`), the format and the template's values.
+    /// `contents` is then the merged file, but zotfile only tracks the template's keys.
+    pub merge: Option<Merge>,
+    /// For a file assembled from fragments (`fragment_of`), the modules contributing to it, in
+    /// the order their fragments appear. `source` is then the first fragment.
+    pub contributors: Vec<String>,
+}
+
+/// What's at the target path of a symlink output.
+#[derive(Debug, PartialEq)]
+pub enum LinkState {
+    Missing,
+    /// A link to the source in the config repo.
+    Linked,
+    /// A link to something that doesn't exist.
+    Broken(PathBuf),
+    /// A link to some other file.
+    Foreign(PathBuf),
+    /// A regular file.
+    File,
+}
+
+impl Output {
+    pub fn path(&self) -> &Path {
+        Path::new(&self.target_path)
+    }
+
+    /// Where a symlink output points: the source, as an absolute path.
+    pub fn link_dest(&self) -> PathBuf {
+        util::absolute_path(&self.source)
+    }
+
+    pub fn link_state(&self) -> errors::Result<LinkState> {
+        let metadata = match fs::symlink_metadata(self.path()) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(LinkState::Missing),
+        };
+
+        if !metadata.file_type().is_symlink() {
+            return Ok(LinkState::File);
+        }
+
+        let dest = fs::read_link(self.path())?;
+        Ok(if dest == self.link_dest() {
+            LinkState::Linked
+        } else if !self.path().exists() {
+            LinkState::Broken(dest)
+        } else {
+            LinkState::Foreign(dest)
+        })
+    }
+
+    /// The part of `contents` zotfile manages: the block, for a managed block (`None` if it
+    /// isn't there); the template's keys, for a merge; or everything.
+    pub fn managed<'c>(&self, contents: &'c [u8]) -> Option<Cow<'c, [u8]>> {
+        if let Some(ref merge) = self.merge {
+            return merge.managed(contents).map(Cow::Owned);
+        }
+
+        match self.block {
+            Some(ref markers) => std::str::from_utf8(contents)
+                .ok()
+                .and_then(|text| markers.find(text))
+                .map(|block| Cow::Borrowed(block.as_bytes())),
+            None => Some(Cow::Borrowed(contents)),
+        }
+    }
+
+    /// The hash of the managed part of what's on disk now, as the manifest would record it.
+    pub fn managed_hash_on_disk(&self) -> errors::Result<Option<String>> {
+        if self.block.is_none() && self.merge.is_none() {
+            return Ok(manifest::hash_on_disk(self.path(), self.link).ok());
+        }
+
+        Ok(self
+            .current()?
+            .and_then(|current| self.managed(&current).map(|m| manifest::hash(&m))))
+    }
+
+    /// The contents as text, or `None` for binary files.
+    pub fn text(&self) -> Option<&str> {
+        std::str::from_utf8(&self.contents).ok()
+    }
+
+    /// Brings a block or a merge up to date with the file it's written into, which may have
+    /// changed since the output was rendered (e.g. another module wrote its block to it). The
+    /// template isn't rendered again: the block, or the template's keys, are put into the file
+    /// as it is now.
+    pub fn refresh(&mut self) -> errors::Result<()> {
+        if self.block.is_none() && self.merge.is_none() {
+            return Ok(());
+        }
+
+        let current = if self.path().is_file() {
+            util::read_file_to_string(self.path())?
+        } else {
+            String::new()
+        };
+
+        if let Some(ref markers) = self.block {
+            let block = std::str::from_utf8(&self.contents)
+                .ok()
+                .and_then(|contents| markers.find(contents))
+                .expect("a rendered block has its markers")
+                .to_owned();
+            self.contents = markers.splice(&current, &block)?.into_bytes();
+        }
+
+        if let Some(ref merge) = self.merge {
+            let mut merged = merge.format.parse(&current).map_err(|e| {
+                errors::Error::new(errors::ErrorKind::Config(format!(
+                    "couldn't parse {} to merge into it: {}",
+                    self.target_path, e
+                )))
+            })?;
+            merge::merge(&mut merged, &merge.template);
+            self.contents = merge.format.serialize(&merged)?.into_bytes();
+        }
+
+        Ok(())
+    }
+
+    /// The target file's current contents, if it exists.
+    pub fn current(&self) -> errors::Result<Option<Vec<u8>>> {
+        if self.path().is_file() {
+            Ok(Some(fs::read(self.path())?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The changes between the target file (empty if it doesn't exist yet) and the new
+    /// contents, or `None` if either side isn't text.
+    pub fn hunks(&self, context: usize) -> errors::Result<Option<Vec<Hunk>>> {
+        let current = self.current()?.unwrap_or_default();
+
+        Ok(match (std::str::from_utf8(&current), self.text()) {
+            (Ok(current), Some(new)) => Some(diff::diff(current, new, context)),
+            _ => None,
+        })
+    }
+
+    /// Whether the target file already has these contents (see `is_applied`). This is what
+    /// `status` compares too.
+    pub fn is_up_to_date(&self) -> errors::Result<bool> {
+        Ok(self
+            .current()?
+            .is_some_and(|current| self.is_applied(&current)))
+    }
+
+    /// Whether `current` already has what this output writes: for a merge, the template's keys,
+    /// since the rest of the file belongs to the app; otherwise every byte, so a file that only
+    /// differs in line endings or its final newline still needs writing.
+    pub fn is_applied(&self, current: &[u8]) -> bool {
+        match self.merge {
+            Some(_) => self
+                .managed(current)
+                .is_some_and(|managed| Some(managed) == self.managed(&self.contents)),
+            None => current == self.contents.as_slice(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::merge::{Format, Merge};
+    use serde_json::json;
+
+    fn output(contents: &str, merge: Option<Merge>) -> Output {
+        Output {
+            source: PathBuf::from("modules/code/templates/settings.json"),
+            target_path: "/h/settings.json".to_owned(),
+            contents: contents.as_bytes().to_vec(),
+            attributes: Default::default(),
+            warning: None,
+            link: false,
+            block: None,
+            merge,
+            contributors: vec![],
+        }
+    }
+
+    #[test]
+    fn test_is_applied() {
+        let replace = output("a\nb\n", None);
+        assert!(replace.is_applied(b"a\nb\n"));
+        assert!(!replace.is_applied(b"a\r\nb\r\n"));
+        assert!(!replace.is_applied(b"a\nb"));
+
+        let merge = output(
+            "{\n  \"editor.fontSize\": 14,\n  \"theme\": \"dark\"\n}\n",
+            Some(Merge {
+                format: Format::Json,
+                template: json!({"editor.fontSize": 14}),
+            }),
+        );
+        assert!(merge.is_applied(b"{\"theme\": \"light\", // mine\n\"editor.fontSize\": 14}"));
+        assert!(!merge.is_applied(b"{\"editor.fontSize\": 12}"));
+        assert!(!merge.is_applied(b"not json"));
+    }
+}
@@ -0,0 +1,118 @@
+use ansi_term::Colour;
+use std::env;
+use std::fs;
+use std::io;
+use std::process::Command;
+
+use crate::diff::Hunk;
+use crate::errors;
+
+const EDIT_INSTRUCTIONS: &str = "\
+# Edit the hunk above. Lines starting with '+' are added and lines starting
+# with '-' are removed; change a '-' to ' ' to keep a line, or delete a '+'
+# line to leave it out. Lines starting with '#' are ignored.
+";
+
+/// Steps through `hunks` one at a time, like `git add -p`, and returns the hunks that were
+/// accepted (possibly edited), in order.
+pub fn select(hunks: Vec<Hunk>, color: bool) -> errors::Result<Vec<Hunk>> {
+    let total = hunks.len();
+    let mut accepted = Vec::new();
+    let mut remaining = None;
+
+    for (i, hunk) in hunks.into_iter().enumerate() {
+        // set by 'a' (accept all) or 'd' / 'q' (reject all)
+        if let Some(accept) = remaining {
+            if accept {
+                accepted.push(hunk);
+            }
+            continue;
+        }
+
+        loop {
+            print!("{}", hunk.display(color));
+            println!(
+                "{}",
+                Colour::Blue.bold().paint(format!(
+                    "({}/{}) Apply this hunk? [y,n,e,a,d,q,?]",
+                    i + 1,
+                    total
+                ))
+            );
+
+            let mut input = String::new();
+            // end of input quits, rather than asking again forever
+            if io::stdin().read_line(&mut input)? == 0 {
+                input.push('q');
+            }
+
+            match input.trim() {
+                "y" | "Y" => {
+                    accepted.push(hunk);
+                    break;
+                }
+                "n" | "N" => break,
+                "a" => {
+                    accepted.push(hunk);
+                    remaining = Some(true);
+                    break;
+                }
+                "d" | "q" => {
+                    remaining = Some(false);
+                    break;
+                }
+                "e" => match edit(&hunk)? {
+                    Some(edited) => {
+                        accepted.push(edited);
+                        break;
+                    }
+                    None => continue,
+                },
+                _ => {
+                    println!("y - apply this hunk");
+                    println!("n - do not apply this hunk");
+                    println!("e - manually edit this hunk");
+                    println!("a - apply this hunk and all remaining hunks");
+                    println!("d - do not apply this hunk or any remaining hunks");
+                    println!("q - quit; do not apply this hunk or any remaining hunks");
+                }
+            }
+        }
+    }
+
+    Ok(accepted)
+}
+
+/// Opens the hunk in `$EDITOR`. Returns `None` (after printing why) if the edited hunk no
+/// longer matches the original file, in which case the hunk is offered again.
+fn edit(hunk: &Hunk) -> errors::Result<Option<Hunk>> {
+    let path = env::temp_dir().join(format!("zotfile-hunk-{}.diff", std::process::id()));
+    fs::write(&path, format!("{}{}", hunk.unified(), EDIT_INSTRUCTIONS))?;
+
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned());
+    let status = Command::new(&editor).arg(&path).status();
+    let contents = fs::read_to_string(&path);
+    fs::remove_file(&path).ok();
+
+    if !status?.success() {
+        println!(
+            "{}",
+            Colour::Red.paint(format!("`{}` exited with an error", editor))
+        );
+        return Ok(None);
+    }
+
+    let edited = hunk.with_body(&contents?);
+
+    if edited.old_lines() != hunk.old_lines() {
+        println!(
+            "{}",
+            Colour::Red.paint("Your edited hunk no longer matches the original file.")
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(edited))
+}
@@ -0,0 +1,176 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::backup::Backup;
+use crate::config;
+use crate::errors::Error;
+use crate::fragment;
+use crate::graph;
+use crate::manifest::Manifest;
+use crate::module::{Module, Rendered};
+use crate::options::Options;
+use crate::output::Output;
+use crate::util;
+
+/// Everything a run would write, rendered before anything is written so that templates
+/// fighting over the same file are caught up front. Modules are then processed with what was
+/// rendered here, rather than rendering their templates again.
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// What each module rendered, in processing order.
+    pub modules: Vec<(String, Rendered)>,
+    /// Files assembled from the fragments of every module.
+    pub assembled: Vec<Output>,
+    /// Modules whose templates or files couldn't be rendered.
+    pub failures: Vec<(String, Error)>,
+}
+
+/// Two outputs writing the same file (or the same block in it).
+#[derive(Debug, PartialEq)]
+pub struct Collision {
+    pub target_path: String,
+    pub first: PathBuf,
+    pub second: PathBuf,
+}
+
+impl fmt::Display for Collision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} is written by both {} and {}; use `fragment_of` or `strategy = \"block\"` to share it",
+            self.target_path,
+            self.first.display(),
+            self.second.display()
+        )
+    }
+}
+
+impl Plan {
+    pub fn new(
+        nodes: &[graph::Node],
+        target_config: &config::Config,
+        options: &Options,
+        backup: &Backup,
+        manifest: &Manifest,
+    ) -> Self {
+        let mut plan = Plan::default();
+        let mut fragments = Vec::new();
+
+        for node in nodes {
+            let module_target_config = config::target_config_for_module(target_config, &node.name);
+            let rendered = Module::new(&node.name, module_target_config, options, backup, manifest)
+                .and_then(|module| module.render());
+
+            match rendered {
+                Ok(rendered) => {
+                    fragments.extend(rendered.fragments.iter().cloned());
+                    plan.modules.push((node.name.clone(), rendered));
+                }
+                Err(e) => plan.failures.push((node.name.clone(), e)),
+            }
+        }
+
+        plan.assembled = fragment::assemble(fragments);
+        plan
+    }
+
+    /// Outputs that would overwrite each other. Blocks only collide with other outputs for
+    /// the same file if they have the same markers, i.e. come from the same module.
+    pub fn collisions(&self) -> Vec<Collision> {
+        let mut seen: Vec<(PathBuf, &Output)> = Vec::new();
+        let mut collisions = Vec::new();
+
+        let outputs = self
+            .modules
+            .iter()
+            .flat_map(|(_, rendered)| &rendered.outputs);
+
+        for output in outputs.chain(&self.assembled) {
+            let path = util::absolute_path(output.path());
+
+            let other = seen.iter().find(|(p, other)| {
+                *p == path && !matches!((&other.block, &output.block), (Some(a), Some(b)) if a != b)
+            });
+
+            match other {
+                Some((_, other)) => collisions.push(Collision {
+                    target_path: output.target_path.clone(),
+                    first: other.source.clone(),
+                    second: output.source.clone(),
+                }),
+                None => seen.push((path, output)),
+            }
+        }
+
+        collisions
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::Markers;
+
+    fn output(source: &str, target_path: &str, block: Option<&str>) -> Output {
+        Output {
+            source: PathBuf::from(source),
+            target_path: target_path.to_owned(),
+            contents: vec![],
+            attributes: Default::default(),
+            warning: None,
+            link: false,
+            block: block.map(|module| Markers::new("# ", module)),
+            merge: None,
+            contributors: vec![],
+        }
+    }
+
+    #[test]
+    fn test_collisions() {
+        let rendered = |outputs| Rendered {
+            outputs,
+            fragments: vec![],
+        };
+        let plan = Plan {
+            modules: vec![
+                (
+                    "a".to_owned(),
+                    rendered(vec![
+                        output("modules/a/templates/rc", "/h/.bashrc", Some("a")),
+                        output("modules/a/templates/vimrc", "/h/.vimrc", None),
+                        output("modules/a/templates/rc2", "/h/.bashrc", Some("a")),
+                    ]),
+                ),
+                (
+                    "b".to_owned(),
+                    rendered(vec![output(
+                        "modules/b/templates/rc",
+                        "/h/.bashrc",
+                        Some("b"),
+                    )]),
+                ),
+                (
+                    "c".to_owned(),
+                    rendered(vec![output("modules/c/templates/vimrc", "/h/.vimrc", None)]),
+                ),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            plan.collisions(),
+            vec![
+                Collision {
+                    target_path: "/h/.bashrc".to_owned(),
+                    first: PathBuf::from("modules/a/templates/rc"),
+                    second: PathBuf::from("modules/a/templates/rc2"),
+                },
+                Collision {
+                    target_path: "/h/.vimrc".to_owned(),
+                    first: PathBuf::from("modules/a/templates/vimrc"),
+                    second: PathBuf::from("modules/c/templates/vimrc"),
+                },
+            ]
+        );
+    }
+}
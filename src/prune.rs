@@ -0,0 +1,140 @@
+use ansi_term::Colour;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::backup::Backup;
+use crate::errors::{self, Error, ErrorKind};
+use crate::manifest::{self, Manifest};
+use crate::merge;
+use crate::options::{Mode, Options};
+use crate::summary::{Outcome, Report};
+use crate::util;
+
+/// Offers to remove files that zotfile applied but that no template produces any more, because
+/// the template (or its whole module) was deleted or its `target_path` changed. Removed files
+/// are backed up first and dropped from the manifest.
+pub fn remove(
+    orphans: &[manifest::Entry],
+    options: &Options,
+    backup: &Backup,
+    manifest: &Manifest,
+) -> errors::Result<Report> {
+    let mut report = Report::default();
+
+    for entry in orphans {
+        report.record(remove_one(entry, options, backup, manifest)?);
+    }
+
+    Ok(report)
+}
+
+fn remove_one(
+    entry: &manifest::Entry,
+    options: &Options,
+    backup: &Backup,
+    manifest: &Manifest,
+) -> errors::Result<Outcome> {
+    let path = Path::new(&entry.target_path);
+    let block = entry.block.as_ref();
+    // the keys a merge last wrote, which are all that's removed from the file
+    let shape: Option<serde_json::Value> = match entry.merge {
+        Some(_) => manifest
+            .applied_contents(entry)
+            .and_then(|json| serde_json::from_str(&json).ok()),
+        None => None,
+    };
+
+    // for a block or a merge, what's on disk is only the part zotfile wrote
+    let on_disk = match (block, entry.merge, shape.as_ref()) {
+        (Some(markers), _, _) => fs::read_to_string(path)
+            .ok()
+            .and_then(|text| markers.find(&text).map(|b| manifest::hash(b.as_bytes()))),
+        (_, Some(format), Some(shape)) => fs::read_to_string(path)
+            .ok()
+            .and_then(|text| format.parse(&text).ok())
+            .map(|value| manifest::hash(&merge::owned_keys(&value, shape))),
+        (_, Some(_), None) => {
+            return Err(Error::new(ErrorKind::Config(format!(
+                "the keys last merged into {} weren't kept; remove them by hand",
+                entry.target_path
+            ))))
+        }
+        _ => manifest::hash_on_disk(path, entry.link).ok(),
+    };
+
+    // already gone; nothing to do but forget it
+    let on_disk = match on_disk {
+        Some(hash) => hash,
+        None => {
+            if !options.dry_run() {
+                manifest.forget(path, block)?;
+            }
+            return Ok(Outcome::Unchanged);
+        }
+    };
+
+    println!(
+        "{} {}",
+        Colour::Green.bold().paint(&entry.target_path),
+        Colour::Yellow.paint(format!(
+            "{} no longer produced by module `{}` (was {}).",
+            match (block, entry.merge) {
+                (Some(_), _) => "has a block that is",
+                (_, Some(_)) => "has merged keys that are",
+                _ => "is",
+            },
+            entry.module,
+            entry.source
+        ))
+    );
+
+    if entry.hash != on_disk && !options.force {
+        println!(
+            "{}",
+            Colour::Red.paint("It has local edits; not removing without --force.")
+        );
+        return Ok(Outcome::Skipped);
+    }
+
+    match options.mode {
+        Mode::DryRun => {
+            println!(
+                "{} {}",
+                Colour::Yellow.paint("Would remove"),
+                Colour::Green.bold().paint(&entry.target_path)
+            );
+            return Ok(Outcome::Pending);
+        }
+        Mode::Interactive => {
+            println!("{}", Colour::Yellow.bold().paint("Remove it? [y/N]"));
+
+            let mut input = String::new();
+            io::stdin().read_line(&mut input)?;
+            if !matches!(input.trim(), "y" | "Y") {
+                return Ok(Outcome::Skipped);
+            }
+        }
+        Mode::Yes => (),
+    }
+
+    backup.save(path)?;
+    match (block, entry.merge, shape) {
+        // the rest of the file isn't ours to remove
+        (Some(markers), _, _) => {
+            let text = markers.remove(&fs::read_to_string(path)?)?;
+            util::write_atomic(path, text.as_bytes(), &Default::default())?;
+        }
+        (_, Some(format), Some(shape)) => {
+            let mut value = format.parse(&fs::read_to_string(path)?)?;
+            merge::remove(&mut value, &shape);
+            let text = format.serialize(&value)?;
+            util::write_atomic(path, text.as_bytes(), &Default::default())?;
+        }
+        _ => fs::remove_file(path)?,
+    }
+    manifest.forget(path, block)?;
+
+    println!("{}", Colour::Green.paint("Removed."));
+    Ok(Outcome::Changed)
+}
@@ -1,6 +1,7 @@
 use ansi_term::Colour;
 use git2::{ErrorCode, Repository};
 use serde::{Deserialize, Serialize};
+use std::path::Path;
 
 use crate::errors;
 
@@ -15,8 +16,22 @@ pub struct RepoConfig {
 // - maybe make the SHA required, so at least there is *some* vetting of the cloned source
 // - maybe just make it a static file downloader for a known commit on the remote repo
 impl RepoConfig {
+    pub fn dry_run(&self) {
+        let action = if Path::new(&self.path).exists() {
+            "Would fetch"
+        } else {
+            "Would clone"
+        };
+
+        println!(
+            "{} {}",
+            Colour::Yellow.bold().paint(action),
+            Colour::Cyan.bold().paint(&self.url)
+        );
+    }
+
     pub fn go_do(&self) -> errors::Result<()> {
-        let _ = match Repository::open(&self.path) {
+        match Repository::open(&self.path) {
             Ok(repo) => {
                 repo.find_remote("origin")?.fetch(&["master"], None, None)?;
             }
@@ -30,7 +45,7 @@ impl RepoConfig {
 
                     Repository::clone(&self.url, &self.path)?;
                 }
-                _ => panic!(e),
+                _ => return Err(e.into()),
             },
         };
 
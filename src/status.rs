@@ -0,0 +1,140 @@
+use ansi_term::Colour;
+
+use crate::errors;
+use crate::manifest;
+use crate::output::{LinkState, Output};
+use crate::util;
+
+/// How a target file on disk compares with its freshly rendered template.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileStatus {
+    UpToDate,
+    /// The file is as zotfile last wrote it, but the rendered template has changed since.
+    Modified,
+    Missing,
+    /// The file was edited outside zotfile since it was last applied (or, for files zotfile
+    /// has no record of, it doesn't carry the zotfile header).
+    UnmanagedEdit,
+    /// The content is up to date, but the mode, owner or group declared in the template's
+    /// frontmatter isn't.
+    Permissions,
+    /// zotfile applied the file, but no template produces it any more (see `zotfile prune`).
+    Orphaned,
+    /// A symlink output whose link points at something that doesn't exist.
+    BrokenLink,
+    /// A symlink output whose link points somewhere other than the config repo.
+    ForeignLink,
+    /// A symlink output with a regular file in its place.
+    NotALink,
+}
+
+impl FileStatus {
+    pub fn is_drift(self) -> bool {
+        self != FileStatus::UpToDate
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            FileStatus::UpToDate => "up-to-date",
+            FileStatus::Modified => "modified",
+            FileStatus::Missing => "missing",
+            FileStatus::UnmanagedEdit => "unmanaged-edit",
+            FileStatus::Permissions => "permissions",
+            FileStatus::Orphaned => "orphaned",
+            FileStatus::BrokenLink => "broken-link",
+            FileStatus::ForeignLink => "foreign-link",
+            FileStatus::NotALink => "not-a-link",
+        }
+    }
+
+    fn colour(self) -> Colour {
+        match self {
+            FileStatus::UpToDate => Colour::Green,
+            FileStatus::Modified => Colour::Yellow,
+            FileStatus::Missing => Colour::Red,
+            FileStatus::UnmanagedEdit => Colour::Purple,
+            FileStatus::Permissions => Colour::Blue,
+            FileStatus::Orphaned => Colour::Fixed(8),
+            FileStatus::BrokenLink => Colour::Red,
+            FileStatus::ForeignLink | FileStatus::NotALink => Colour::Purple,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub module: String,
+    pub target_path: String,
+    pub status: FileStatus,
+}
+
+pub fn check(
+    output: &Output,
+    last_applied: Option<&manifest::Entry>,
+) -> errors::Result<FileStatus> {
+    if output.link {
+        return Ok(match output.link_state()? {
+            LinkState::Missing => FileStatus::Missing,
+            LinkState::Linked => FileStatus::UpToDate,
+            LinkState::Broken(_) => FileStatus::BrokenLink,
+            LinkState::Foreign(_) => FileStatus::ForeignLink,
+            LinkState::File => FileStatus::NotALink,
+        });
+    }
+
+    let current = match output.current()? {
+        Some(current) => current,
+        None => return Ok(FileStatus::Missing),
+    };
+
+    Ok(if output.is_applied(&current) {
+        if output.attributes.differ(output.path())? {
+            FileStatus::Permissions
+        } else {
+            FileStatus::UpToDate
+        }
+    } else if output.block.is_some() && output.managed(&current).is_none() {
+        FileStatus::Missing
+    } else if let Some(entry) = last_applied {
+        if output
+            .managed(&current)
+            .map(|m| manifest::hash(&m))
+            .as_ref()
+            == Some(&entry.hash)
+        {
+            FileStatus::Modified
+        } else {
+            FileStatus::UnmanagedEdit
+        }
+    } else if output
+        .warning
+        .as_ref()
+        .is_some_and(|w| String::from_utf8_lossy(&current).contains(w.as_str()))
+    {
+        FileStatus::Modified
+    } else {
+        FileStatus::UnmanagedEdit
+    })
+}
+
+/// Prints one line per entry: status, module and target path, in aligned columns.
+pub fn print(entries: &[Entry], color: bool) {
+    let width = entries.iter().map(|e| e.module.len()).max().unwrap_or(0);
+
+    for entry in entries {
+        println!(
+            "  {}  {}  {}",
+            util::paint(
+                entry.status.colour().bold(),
+                &format!("{:14}", entry.status.label()),
+                color
+            ),
+            util::paint(
+                Colour::Cyan,
+                &format!("{:width$}", entry.module, width = width),
+                color
+            ),
+            entry.target_path
+        );
+    }
+}
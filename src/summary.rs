@@ -0,0 +1,101 @@
+use ansi_term::Colour;
+
+use crate::errors;
+use crate::util;
+
+/// The result of processing a single template.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Changed,
+    Unchanged,
+    Skipped,
+    /// Would change, but nothing was written (`--dry-run`).
+    Pending,
+}
+
+/// Per-module tally of template outcomes.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub changed: usize,
+    pub unchanged: usize,
+    pub skipped: usize,
+    pub pending: usize,
+}
+
+impl Report {
+    pub fn record(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Changed => self.changed += 1,
+            Outcome::Unchanged => self.unchanged += 1,
+            Outcome::Skipped => self.skipped += 1,
+            Outcome::Pending => self.pending += 1,
+        }
+    }
+
+    pub fn merge(&mut self, other: Report) {
+        self.changed += other.changed;
+        self.unchanged += other.unchanged;
+        self.skipped += other.skipped;
+        self.pending += other.pending;
+    }
+}
+
+/// Combined results for every module processed in a run, in processing order.
+#[derive(Debug, Default)]
+pub struct Summary {
+    modules: Vec<(String, errors::Result<Report>)>,
+}
+
+impl Summary {
+    pub fn record(&mut self, module: &str, result: errors::Result<Report>) {
+        self.modules.push((module.to_owned(), result));
+    }
+
+    pub fn has_failures(&self) -> bool {
+        self.modules.iter().any(|(_, result)| result.is_err())
+    }
+
+    pub fn has_pending(&self) -> bool {
+        self.modules
+            .iter()
+            .any(|(_, result)| result.as_ref().is_ok_and(|r| r.pending > 0))
+    }
+
+    pub fn print(&self, color: bool) {
+        let paint = |style, text: &str| util::paint(style, text, color);
+
+        let width = self
+            .modules
+            .iter()
+            .map(|(name, _)| name.len())
+            .max()
+            .unwrap_or(0);
+
+        println!("\n{}", paint(Colour::White.bold(), "Summary"));
+
+        for (name, result) in &self.modules {
+            let name = format!("{:width$}", name, width = width);
+            match result {
+                Ok(report) if report.pending > 0 => println!(
+                    "  {}  {} pending, {} unchanged",
+                    paint(Colour::Cyan.bold(), &name),
+                    paint(Colour::Yellow.normal(), &report.pending.to_string()),
+                    report.unchanged,
+                ),
+                Ok(report) => println!(
+                    "  {}  {} changed, {} unchanged, {} skipped",
+                    paint(Colour::Cyan.bold(), &name),
+                    paint(Colour::Green.normal(), &report.changed.to_string()),
+                    report.unchanged,
+                    paint(Colour::Yellow.normal(), &report.skipped.to_string()),
+                ),
+                Err(e) => println!(
+                    "  {}  {} {}",
+                    paint(Colour::Cyan.bold(), &name),
+                    paint(Colour::Red.bold(), "failed:"),
+                    e
+                ),
+            }
+        }
+    }
+}
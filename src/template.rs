@@ -1,32 +1,36 @@
 use handlebars::{to_json, Handlebars};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 use std::collections::HashMap;
-use std::io::prelude::*;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
 
-use crate::config::{Config, HostConfig};
+use crate::config::{self, Config, HostConfig};
 use crate::errors;
 use crate::util;
 
 #[derive(Debug)]
 pub struct Template<'a> {
+    /// The file the template was read from, for error messages.
+    path: String,
     host_config: &'a HostConfig,
     template_string: String,
-    target_config: &'a Config, // machine-specific config
+    target_config: Config, // machine-specific config, with the template's `vars` and `--set`
     module_config: &'a Config, // module-specific config, e.g., modules/<mod>/config.toml
-    template_config: Config,   // template-specific variables
+    template_config: Config, // template-specific variables
 }
 
 impl<'a> Template<'a> {
+    /// Reads a template and its frontmatter. The frontmatter is rendered first, so it can use
+    /// the same variables as the template; its `[vars]` table is then merged over the target
+    /// config, and the variables from `--set` over that.
     pub fn new_from_file(
         template_path: &str,
         host_config: &'a HostConfig,
-        target_config: &'a Config,
+        target_config: &Config,
         module_config: &'a Config,
+        set: &Config,
     ) -> errors::Result<Self> {
         fn read_template(path: &str) -> errors::Result<(String, String)> {
-            let raw_contents = util::read_file_to_string(&Path::new(path))?;
+            let raw_contents = util::read_file_to_string(Path::new(path))?;
             let mut frontmatter = String::new();
             let mut contents = String::new();
             let mut in_frontmatter = false;
@@ -40,7 +44,7 @@ impl<'a> Template<'a> {
                         in_frontmatter = false;
                     }
                     (_, line, true) => {
-                        (&mut frontmatter).push_str(&format!("{}\n", line));
+                        frontmatter.push_str(&format!("{}\n", line));
                     }
                     (_, line, false) => {
                         contents.push_str(&format!("{}\n", line));
@@ -53,35 +57,52 @@ impl<'a> Template<'a> {
 
         let (template, template_config_raw) = read_template(template_path)?;
 
+        let layered = |vars: Option<&toml::Value>| {
+            let mut config = target_config.clone();
+            for layer in vars.into_iter().chain(set) {
+                match config {
+                    Some(ref mut config) => config::merge(config, layer),
+                    None => config = Some(layer.clone()),
+                }
+            }
+            config
+        };
+
         // initial render of frontmatter only
-        let template_config = Self::new(
+        let template_config: Config = Self::new(
+            template_path,
             &template_config_raw,
-            &host_config,
-            &target_config,
+            host_config,
+            layered(None),
             None,
-            &None,
+            module_config,
         )
-        .render()
+        .render()?
         .parse()
         .ok();
 
+        let vars = template_config.as_ref().and_then(|c| c.get("vars"));
+
         Ok(Self::new(
+            template_path,
             &template,
             host_config,
-            target_config,
-            template_config,
+            layered(vars),
+            template_config.clone(),
             module_config,
         ))
     }
 
     pub fn new(
+        path: &str,
         template_string: &str,
         host_config: &'a HostConfig,
-        target_config: &'a Config,
+        target_config: Config,
         template_config: Config,
         module_config: &'a Config,
     ) -> Self {
         Template {
+            path: path.to_owned(),
             host_config,
             template_string: String::from(template_string),
             target_config,
@@ -90,28 +111,85 @@ impl<'a> Template<'a> {
         }
     }
 
-    pub fn render_with_warning(&self) -> String {
-        let reg = Handlebars::new();
-        let rendered = format!("{}\n{}", self.warning(), self.template_string);
-        reg.render_template(&rendered, &to_json(&self)).unwrap()
+    pub fn render_with_warning(&self) -> errors::Result<String> {
+        self.render_string(&format!("{}\n{}", self.warning(), self.template_string))
+    }
+
+    pub fn render(&self) -> errors::Result<String> {
+        self.render_string(&self.template_string)
     }
 
-    pub fn render(&self) -> String {
-        let reg = Handlebars::new();
-        reg.render_template(&self.template_string, &to_json(&self))
-            .unwrap()
+    fn render_string(&self, template: &str) -> errors::Result<String> {
+        Handlebars::new()
+            .render_template(template, &to_json(self))
+            .map_err(|e| {
+                errors::Error::new(errors::ErrorKind::Config(format!("{}: {}", self.path, e)))
+            })
     }
 
     pub fn target_path(&self) -> Option<&str> {
-        let o = match self.template_config {
-            Some(ref c) => c.get("target_path"),
-            _ => None,
-            _ => None,
-            _ => None,
+        self.frontmatter_str("target_path")
+    }
+
+    /// Sets a frontmatter key, unless the template's own frontmatter already sets it.
+    pub fn default_frontmatter(&mut self, key: &str, value: toml::Value) {
+        let mut config = match self.template_config.take() {
+            Some(toml::Value::Table(table)) => table,
+            _ => toml::value::Table::new(),
+        };
+        config.entry(key).or_insert(value);
+        self.template_config = Some(toml::Value::Table(config));
+    }
+
+    fn frontmatter_str(&self, key: &str) -> Option<&str> {
+        self.template_config.as_ref()?.get(key)?.as_str()
+    }
+
+    /// The file mode, owner and group declared in frontmatter (`mode = "0600"`,
+    /// `owner = "zach"`, `group = "staff"`).
+    pub fn attributes(&self) -> errors::Result<util::Attributes> {
+        let mode = match self.template_config.as_ref().and_then(|c| c.get("mode")) {
+            Some(mode) => Some(parse_mode(mode)?),
+            None => None,
         };
 
-        o.expect("target_path should be in template frontmatter")
-            .as_str()
+        Ok(util::Attributes {
+            mode,
+            uid: self.frontmatter_str("owner").map(util::uid).transpose()?,
+            gid: self.frontmatter_str("group").map(util::gid).transpose()?,
+        })
+    }
+
+    /// The line comment prefix for this template's file type (`comment_format`, default `# `).
+    pub fn comment_format(&self) -> &str {
+        self.frontmatter_str("comment_format").unwrap_or("# ")
+    }
+
+    /// How the output is written (`strategy`): `replace` (the default) owns the whole file,
+    /// `block` only a marked block inside it, and `merge` only its own keys in a structured
+    /// file.
+    pub fn strategy(&self) -> &str {
+        self.frontmatter_str("strategy").unwrap_or("replace")
+    }
+
+    /// The file format for `strategy = "merge"` (`format`), if set.
+    pub fn format(&self) -> Option<&str> {
+        self.frontmatter_str("format")
+    }
+
+    /// The file this template is a fragment of (`fragment_of`), if it's part of a file
+    /// assembled from several modules rather than a file of its own.
+    pub fn fragment_of(&self) -> Option<&str> {
+        self.frontmatter_str("fragment_of")
+    }
+
+    /// Where a fragment goes in its file (`order`, default 0): lowest first.
+    pub fn order(&self) -> i64 {
+        self.template_config
+            .as_ref()
+            .and_then(|c| c.get("order"))
+            .and_then(|o| o.as_integer())
+            .unwrap_or(0)
     }
 
     pub fn warning(&self) -> String {
@@ -125,14 +203,7 @@ impl<'a> Template<'a> {
 
         let mut result = String::new();
 
-        let comment_formatter = match self.template_config {
-            Some(ref conf) => match conf.get("comment_format") {
-                Some(toml) => toml.as_str(),
-                _ => None,
-            },
-            _ => None,
-        }
-        .unwrap_or("# ");
+        let comment_formatter = self.comment_format();
 
         for part in parts {
             result.push_str(&format!("{}{}\n", comment_formatter, part));
@@ -141,62 +212,25 @@ impl<'a> Template<'a> {
         result
     }
 
-    pub fn diff(&self) -> String {
-        // FIXME stop unwrapping
-
-        let mut p = Command::new("git")
-            .stderr(Stdio::piped())
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            // "color" option ensures ansi codes are rendered into the stdout pipe
-            .args(&[
-                "diff",
-                "--no-index",
-                "--color",
-                &self.target_path().clone().unwrap(),
-                "-",
-            ])
-            .spawn()
-            .unwrap();
-
-        p.stdin
-            .as_mut()
-            .map(|x| x.write_all(self.render_with_warning().as_bytes()));
-
-        let output = p.wait_with_output().unwrap();
-        let result = String::from_utf8(output.stdout).unwrap();
-        // println!("{}", &result);
-        result
-    }
-
-    pub fn copy_command(&self) -> String {
-        if let Some(conf) = &self.target_config {
-            match conf.get("clipboard") {
-                Some(ref tv) => {
-                    let s = tv.as_str().unwrap().clone();
-                    match s {
-                        "xclip" => String::from("xclip -i -selection clipboard"),
-                        "xsel" => String::from("xsel -i --clipboard"),
-                        "pbcopy" => String::from("pbcopy"),
-                        s => panic!("clipboard {:?} not configured", s),
-                    }
-                }
-                None => panic!("clipboard not configured"),
-            }
-        } else {
-            // None
-            panic!("no...");
+    /// The command that copies stdin to the clipboard, for the target's `clipboard` (`xclip`,
+    /// `xsel` or `pbcopy`), if it sets a known one.
+    pub fn copy_command(&self) -> Option<&'static str> {
+        match self.target_config.as_ref()?.get("clipboard")?.as_str()? {
+            "xclip" => Some("xclip -i -selection clipboard"),
+            "xsel" => Some("xsel -i --clipboard"),
+            "pbcopy" => Some("pbcopy"),
+            _ => None,
         }
     }
 
     pub fn dirs(&self) -> HashMap<&str, PathBuf> {
         let mut h = HashMap::new();
-        dirs::home_dir().map(|d| {
+        if let Some(d) = dirs::home_dir() {
             h.insert("home", d.clone());
             // NOTE: dirs::config_dir() points to ~/Library/Preferences, which is not what we want
             // in most cases.
             h.insert("config", d.join(Path::new(".config")));
-        });
+        }
 
         h
     }
@@ -218,10 +252,59 @@ impl<'a> Serialize for Template<'a> {
     }
 }
 
+/// Reads a frontmatter `mode`. Only octal strings are accepted: TOML reads `mode = 644` as the
+/// decimal 644, which is almost never what was meant.
+fn parse_mode(mode: &toml::Value) -> errors::Result<u32> {
+    let invalid = |message: String| errors::Error::new(errors::ErrorKind::Config(message));
+
+    match mode {
+        toml::Value::String(mode) => u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "invalid mode `{}` (expected an octal string like \"0644\")",
+                    mode
+                ))
+            }),
+        toml::Value::Integer(mode) => Err(invalid(format!(
+            "mode = {} is read as a decimal number; quote it, e.g. mode = \"0644\"",
+            mode
+        ))),
+        mode => Err(invalid(format!("invalid mode `{}`", mode))),
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_mode() {
+        let mode = |s: &str| parse_mode(&toml::from_str::<toml::Value>(s).unwrap()["mode"]);
+
+        assert_eq!(mode("mode = \"0644\"").unwrap(), 0o644);
+        assert_eq!(mode("mode = \"0o600\"").unwrap(), 0o600);
+        assert_eq!(mode("mode = \"755\"").unwrap(), 0o755);
+        assert!(mode("mode = 644").is_err());
+        assert!(mode("mode = -1").is_err());
+        assert!(mode("mode = \"0899\"").is_err());
+        assert!(mode("mode = \"01000000\"").is_err());
+    }
+
+    #[test]
+    fn test_render_error() {
+        let host_config = HostConfig::detect();
+        let template = |text: &str| Template::new("t", text, host_config, None, None, &None);
+
+        // no `clipboard` in the target config
+        assert_eq!(template("{{copy_command}}").render().unwrap(), "");
+        let error = template("{{#if host.ssh}}\nx\n").render().unwrap_err();
+        assert!(error.to_string().starts_with("t: "));
+    }
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn test_sanity() {
         assert!(true);
     }
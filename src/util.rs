@@ -1,12 +1,15 @@
-use std::fs::File;
+use ansi_term::Style;
+use std::fs::{self, File};
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::path::Path;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::config::{Config, Platform};
+use crate::config::{Config, Distro, Platform};
 use crate::errors;
 
-pub fn whoami<'a>() -> String {
+pub fn whoami() -> String {
     let stdout = std::process::Command::new("whoami")
         .output()
         .expect("tried to get username")
@@ -16,28 +19,57 @@ pub fn whoami<'a>() -> String {
 
 pub fn hostname() -> String {
     let stdout = std::process::Command::new("uname")
-        .args(&["-n"])
+        .args(["-n"])
         .output()
         .expect("tried to get username")
         .stdout;
     std::str::from_utf8(&stdout).expect("").trim().into()
 }
 
+/// Detects the platform: the Linux distribution from os-release, the macOS version from
+/// `sw_vers`, or a BSD's release from `uname -r`.
 pub fn platform() -> Platform {
-    let stdout = std::process::Command::new("uname")
-        .args(&["-o"])
-        .output()
-        .expect("tried to get username")
-        .stdout;
-    let p = std::str::from_utf8(&stdout).expect("").trim();
-    match p {
-        "GNU/Linux" => Platform::Linux(None),
+    match std::env::consts::OS {
+        "linux" => Platform::Linux(
+            ["/etc/os-release", "/usr/lib/os-release"]
+                .iter()
+                .find_map(|path| fs::read_to_string(path).ok())
+                .map(|text| Distro::from_os_release(&text)),
+        ),
+        "macos" => Platform::Darwin(command_output("sw_vers", &["-productVersion"])),
+        os @ ("freebsd" | "openbsd" | "netbsd" | "dragonfly") => {
+            Platform::Bsd(os.to_owned(), command_output("uname", &["-r"]))
+        }
         _ => Platform::Unknown,
     }
 }
 
+/// The trimmed standard output of a command, if it runs successfully.
+pub fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// `text` painted with `style`, or left plain when colour is off (`--color=never`, or output
+/// that isn't a terminal).
+pub fn paint(style: impl Into<Style>, text: &str, color: bool) -> String {
+    if color {
+        style.into().paint(text).to_string()
+    } else {
+        text.to_owned()
+    }
+}
+
 pub fn read_file_to_string(path: &Path) -> errors::Result<String> {
-    let file = File::open(path).expect(&format!("file {:?} not found", path));
+    let file = File::open(path).unwrap_or_else(|_| panic!("file {:?} not found", path));
     let mut buf_reader = BufReader::new(file);
     let mut contents = String::new();
     buf_reader.read_to_string(&mut contents)?;
@@ -45,6 +77,421 @@ pub fn read_file_to_string(path: &Path) -> errors::Result<String> {
 }
 
 pub fn load_toml_file(path: &Path) -> errors::Result<Config> {
-    let result = read_file_to_string(path)?.parse::<toml::Value>().ok();
-    Ok(result)
+    let value = read_file_to_string(path)?
+        .parse::<toml::Value>()
+        .map_err(|e| {
+            errors::Error::new(errors::ErrorKind::Config(format!(
+                "{}: {}",
+                path.display(),
+                e
+            )))
+        })?;
+    Ok(Some(value))
+}
+
+/// Permissions and ownership to enforce on a file. `None` fields are left as they are (or, for
+/// a replaced file, carried over from the file being replaced).
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Attributes {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Attributes {
+    /// Whether the existing file at `path` has a different mode, owner or group.
+    pub fn differ(&self, path: &Path) -> errors::Result<bool> {
+        let metadata = fs::metadata(path)?;
+
+        Ok(self.mode.is_some_and(|m| m != metadata.mode() & 0o7777)
+            || self.uid.is_some_and(|u| u != metadata.uid())
+            || self.gid.is_some_and(|g| g != metadata.gid()))
+    }
+
+    pub fn apply(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+
+        if self.uid.is_some() || self.gid.is_some() {
+            std::os::unix::fs::chown(path, self.uid, self.gid)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Replaces `path` with `contents` atomically: the data is written and synced to a temporary
+/// file in the same directory, which is then renamed over the target, so a crash or a full disk
+/// never leaves a truncated file behind. `attributes` are applied to the new file; anything
+/// they leave unset is carried over from the file being replaced, if there is one.
+///
+/// If `path` is a symlink (e.g. into a stow directory), the file it points to is replaced
+/// instead, so the link survives.
+pub fn write_atomic(path: &Path, contents: &[u8], attributes: &Attributes) -> errors::Result<()> {
+    let write_error = |e: std::io::Error| {
+        errors::Error::new(errors::ErrorKind::Write(path.to_path_buf(), e.kind()))
+    };
+
+    let resolved;
+    let path = if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink()) {
+        resolved = fs::canonicalize(path).map_err(write_error)?;
+        resolved.as_path()
+    } else {
+        path
+    };
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir).map_err(write_error)?;
+
+    let existing = fs::metadata(path).ok();
+    let mode = attributes
+        .mode
+        .or_else(|| existing.as_ref().map(|m| m.mode() & 0o7777));
+
+    let file_name = path.file_name().expect("target path has a file name");
+    let tmp_path = dir.join(format!(
+        ".{}.zotfile-{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    let result = (|| {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+
+        if let Some(mode) = mode {
+            fs::set_permissions(&tmp_path, fs::Permissions::from_mode(mode))?;
+        }
+
+        if attributes.uid.is_some() || attributes.gid.is_some() {
+            std::os::unix::fs::chown(&tmp_path, attributes.uid, attributes.gid)?;
+        } else if let Some(ref existing) = existing {
+            // best effort: only root can hand a file to another user
+            let tmp = fs::metadata(&tmp_path)?;
+            if (tmp.uid(), tmp.gid()) != (existing.uid(), existing.gid()) {
+                std::os::unix::fs::chown(&tmp_path, Some(existing.uid()), Some(existing.gid()))
+                    .ok();
+            }
+        }
+
+        fs::rename(&tmp_path, path)
+    })();
+
+    if let Err(e) = result {
+        fs::remove_file(&tmp_path).ok();
+        return Err(write_error(e));
+    }
+
+    Ok(())
+}
+
+/// Points `path` at `dest` with a symlink, replacing whatever is at `path` in a single rename.
+pub fn symlink_atomic(dest: &Path, path: &Path) -> errors::Result<()> {
+    let write_error = |e: std::io::Error| {
+        errors::Error::new(errors::ErrorKind::Write(path.to_path_buf(), e.kind()))
+    };
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir).map_err(write_error)?;
+
+    let file_name = path.file_name().expect("target path has a file name");
+    let tmp_path = dir.join(format!(
+        ".{}.zotfile-{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    let result =
+        std::os::unix::fs::symlink(dest, &tmp_path).and_then(|_| fs::rename(&tmp_path, path));
+
+    if let Err(e) = result {
+        fs::remove_file(&tmp_path).ok();
+        return Err(write_error(e));
+    }
+
+    Ok(())
+}
+
+/// Looks up a user id by name (or accepts a numeric id).
+pub fn uid(user: &str) -> errors::Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+
+    let output = std::process::Command::new("id")
+        .args(["-u", user])
+        .output()?;
+
+    std::str::from_utf8(&output.stdout)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .filter(|_| output.status.success())
+        .ok_or_else(|| {
+            errors::Error::new(errors::ErrorKind::Config(format!(
+                "unknown user `{}`",
+                user
+            )))
+        })
+}
+
+/// Looks up a group id by name (or accepts a numeric id), through the system's directory
+/// services: `getent` where there is one, `dscl` on macOS.
+pub fn gid(group: &str) -> errors::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let getent = || {
+        // name:password:gid:members
+        command_output("getent", &["group", group])?
+            .split(':')
+            .nth(2)?
+            .parse()
+            .ok()
+    };
+    let dscl = || {
+        // PrimaryGroupID: 20
+        command_output(
+            "dscl",
+            &[
+                ".",
+                "-read",
+                &format!("/Groups/{}", group),
+                "PrimaryGroupID",
+            ],
+        )?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+    };
+
+    getent().or_else(dscl).ok_or_else(|| {
+        errors::Error::new(errors::ErrorKind::Config(format!(
+            "unknown group `{}`",
+            group
+        )))
+    })
+}
+
+/// Every file under `dir`, recursively, sorted by path. Symlinked directories are followed,
+/// unless they lead back to a directory being walked; broken symlinks are skipped.
+pub fn walk(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    // each directory to walk, with the real paths of it and the directories it is in
+    let mut dirs = vec![(dir.to_path_buf(), vec![fs::canonicalize(dir)?])];
+
+    while let Some((dir, ancestors)) = dirs.pop() {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+
+            let is_dir = if file_type.is_symlink() {
+                match fs::metadata(&path) {
+                    Ok(metadata) => metadata.is_dir(),
+                    Err(_) => continue,
+                }
+            } else {
+                file_type.is_dir()
+            };
+
+            if !is_dir {
+                files.push(path);
+                continue;
+            }
+
+            let real = fs::canonicalize(&path)?;
+            if !ancestors.contains(&real) {
+                let mut ancestors = ancestors.clone();
+                ancestors.push(real);
+                dirs.push((path, ancestors));
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Zotfile's state directory: `$XDG_STATE_HOME/zotfile`, defaulting to `~/.local/state/zotfile`.
+pub fn state_dir() -> PathBuf {
+    let base = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => dirs::home_dir()
+            .expect("home directory")
+            .join(".local/state"),
+    };
+
+    base.join("zotfile")
+}
+
+/// Creates `dir` (and any missing parents) readable only by the current user, tightening it if
+/// it already exists: the state dir keeps copies of applied files, which may hold secrets.
+pub fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+}
+
+/// Makes `path` absolute by joining it to the current directory (without resolving symlinks).
+pub fn absolute_path(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()
+            .expect("current directory")
+            .join(path)
+    }
+}
+
+/// Whether `text` matches a shell-style `pattern`, where `*` matches any run of characters and
+/// `?` any single character.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // the positions to resume from after the last `*`, for backtracking
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Expands a leading `~/` in `path` to the home directory.
+pub fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest).to_string_lossy().into_owned(),
+        _ => path.to_owned(),
+    }
+}
+
+/// The current UTC time as a sortable, filename-safe timestamp, e.g. `20190314T091500Z`.
+pub fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before 1970")
+        .as_secs();
+
+    format_timestamp(secs)
+}
+
+fn format_timestamp(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+// days since 1970-01-01 to (year, month, day); see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u64, u64) {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = (z - era * 146_097) as u64;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe as i64 + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "19700101T000000Z");
+        assert_eq!(format_timestamp(951_827_696), "20000229T123456Z");
+        assert_eq!(format_timestamp(1_552_554_900), "20190314T091500Z");
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("build-*", "build-07"));
+        assert!(glob_match("*-mbp", "zach-mbp"));
+        assert!(glob_match("web-??.*", "web-01.prod"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("build-*", "builder"));
+        assert!(!glob_match("web-?", "web-01"));
+    }
+
+    #[test]
+    fn test_write_atomic_through_symlink() {
+        let tmp = std::env::temp_dir().join(format!("zotfile-write-{}", std::process::id()));
+        let real = tmp.join("stow/vim/.vimrc");
+        let link = tmp.join(".vimrc");
+        fs::create_dir_all(real.parent().unwrap()).unwrap();
+        fs::write(&real, "set nu\n").unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        write_atomic(&link, b"set nonu\n", &Default::default()).unwrap();
+
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_link(&link).unwrap(), real);
+        assert_eq!(fs::read_to_string(&real).unwrap(), "set nonu\n");
+
+        fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    #[test]
+    fn test_walk_follows_symlinked_dirs() {
+        let tmp = std::env::temp_dir().join(format!("zotfile-walk-{}", std::process::id()));
+        fs::create_dir_all(tmp.join("a")).unwrap();
+        fs::write(tmp.join("a/rc"), "").unwrap();
+        std::os::unix::fs::symlink(tmp.join("a"), tmp.join("b")).unwrap();
+        std::os::unix::fs::symlink(&tmp, tmp.join("a/up")).unwrap();
+        std::os::unix::fs::symlink(tmp.join("missing"), tmp.join("broken")).unwrap();
+
+        assert_eq!(
+            walk(&tmp).unwrap(),
+            vec![tmp.join("a/rc"), tmp.join("b/rc")]
+        );
+
+        fs::remove_dir_all(&tmp).unwrap();
+    }
 }